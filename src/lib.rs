@@ -1,6 +1,6 @@
 use crate::{
     light::{LightNode, LightPlugin},
-    mesh_material::MeshMaterialPlugin,
+    mesh_material::{ExcludeFromGi, MeshMaterialPlugin},
     overlay::{OverlayNode, OverlayPlugin},
     post_process::{PostProcessNode, PostProcessPlugin},
     prepass::{PrepassNode, PrepassPlugin},
@@ -65,6 +65,8 @@ pub const MESH_MATERIAL_BINDINGS_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 5025976374517268);
 pub const DEFERRED_BINDINGS_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 14467895678105108252);
+pub const BVH_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 12580953176328061587);
 pub const RESERVOIR_TYPES_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 7770589395703787378);
 pub const RESERVOIR_BINDINGS_SHADER_HANDLE: HandleUntyped =
@@ -131,6 +133,12 @@ impl Plugin for HikariPlugin {
             "shaders/deferred_bindings.wgsl",
             Shader::from_wgsl
         );
+        load_internal_asset!(
+            app,
+            BVH_SHADER_HANDLE,
+            "shaders/bvh.wgsl",
+            Shader::from_wgsl
+        );
         load_internal_asset!(
             app,
             PREPASS_SHADER_HANDLE,
@@ -222,6 +230,7 @@ impl Plugin for HikariPlugin {
             .register_type::<HikariSettings>()
             .register_type::<Taa>()
             .register_type::<Upscale>()
+            .register_type::<ExcludeFromGi>()
             .init_resource::<HikariUniversalSettings>()
             .add_plugin(ExtractResourcePlugin::<NoiseTextures>::default())
             .add_plugin(ExtractResourcePlugin::<HikariUniversalSettings>::default())
@@ -377,6 +386,14 @@ pub struct HikariUniversalSettings {
     pub build_mesh_acceleration_structure: bool,
     /// Whether to build acceleration structure for scene instances.
     pub build_instance_acceleration_structure: bool,
+    /// Whether mesh assets with identical geometry share their buffer regions.
+    /// This costs hashing every loaded mesh.
+    /// Send [`RebuildMeshAssets`](mesh_material::RebuildMeshAssets) after changing this.
+    pub deduplicate_meshes: bool,
+    /// Whether to build acceleration structure for all mesh assets,
+    /// instead of only those used by entities.
+    /// Send [`RebuildMeshAssets`](mesh_material::RebuildMeshAssets) after changing this.
+    pub extract_all_meshes: bool,
 }
 
 impl Default for HikariUniversalSettings {
@@ -384,6 +401,8 @@ impl Default for HikariUniversalSettings {
         Self {
             build_mesh_acceleration_structure: true,
             build_instance_acceleration_structure: true,
+            deduplicate_meshes: false,
+            extract_all_meshes: false,
         }
     }
 }
@@ -21,6 +21,7 @@ use bevy::{
         Extract, RenderApp, RenderStage,
     },
     transform::TransformSystem,
+    utils::HashSet,
 };
 use bvh::bvh::BVH;
 use itertools::Itertools;
@@ -127,6 +128,12 @@ impl ExtractComponent for PreviousMeshUniform {
     }
 }
 
+/// Marker component that keeps a mesh out of global illumination:
+/// it is still rendered, but neither occludes, reflects nor emits light for other surfaces.
+#[derive(Debug, Default, Clone, Copy, Component, Reflect)]
+#[reflect(Component)]
+pub struct ExcludeFromGi;
+
 pub enum InstanceEvent<M: Into<StandardMaterial> + Asset> {
     Created(Entity, Handle<Mesh>, Handle<M>, ComputedVisibility),
     Modified(Entity, Handle<Mesh>, Handle<M>, ComputedVisibility),
@@ -137,6 +144,7 @@ pub enum InstanceEvent<M: Into<StandardMaterial> + Asset> {
 fn instance_event_system<M: Into<StandardMaterial> + Asset>(
     mut events: EventWriter<InstanceEvent<M>>,
     removed: RemovedComponents<Handle<Mesh>>,
+    included: RemovedComponents<ExcludeFromGi>,
     mut set: ParamSet<(
         Query<
             (Entity, &Handle<Mesh>, &Handle<M>, &ComputedVisibility),
@@ -149,8 +157,10 @@ fn instance_event_system<M: Into<StandardMaterial> + Asset>(
                 Changed<Handle<Mesh>>,
                 Changed<Handle<M>>,
                 Changed<ComputedVisibility>,
+                Changed<ExcludeFromGi>,
             )>,
         >,
+        Query<(Entity, &Handle<Mesh>, &Handle<M>, &ComputedVisibility)>,
     )>,
 ) {
     for entity in removed.iter() {
@@ -172,25 +182,35 @@ fn instance_event_system<M: Into<StandardMaterial> + Asset>(
             visibility.clone(),
         ));
     }
+    for (entity, mesh, material, visibility) in set.p2().iter_many(included.iter()) {
+        events.send(InstanceEvent::Modified(
+            entity,
+            mesh.clone_weak(),
+            material.clone_weak(),
+            visibility.clone(),
+        ));
+    }
 }
 
-#[allow(clippy::type_complexity)]
+type ExtractedInstance = (
+    Entity,
+    Aabb,
+    GlobalTransform,
+    Handle<Mesh>,
+    HandleUntyped,
+    ComputedVisibility,
+    bool,
+);
+
 #[derive(Default, Resource)]
 pub struct ExtractedInstances {
-    extracted: Vec<(
-        Entity,
-        Aabb,
-        GlobalTransform,
-        Handle<Mesh>,
-        HandleUntyped,
-        ComputedVisibility,
-    )>,
+    extracted: Vec<ExtractedInstance>,
     removed: Vec<Entity>,
 }
 
 fn extract_instances<M: Into<StandardMaterial> + Asset>(
     mut events: Extract<EventReader<InstanceEvent<M>>>,
-    query: Extract<Query<(&Aabb, &GlobalTransform)>>,
+    query: Extract<Query<(&Aabb, &GlobalTransform, Option<&ExcludeFromGi>)>>,
     mut extracted_instances: ResMut<ExtractedInstances>,
 ) {
     let mut extracted = vec![];
@@ -200,7 +220,7 @@ fn extract_instances<M: Into<StandardMaterial> + Asset>(
         match event {
             InstanceEvent::Created(entity, mesh, material, visibility)
             | InstanceEvent::Modified(entity, mesh, material, visibility) => {
-                if let Ok((aabb, transform)) = query.get(*entity) {
+                if let Ok((aabb, transform, exclude)) = query.get(*entity) {
                     extracted.push((
                         *entity,
                         aabb.clone(),
@@ -208,6 +228,7 @@ fn extract_instances<M: Into<StandardMaterial> + Asset>(
                         mesh.clone_weak(),
                         material.clone_weak_untyped(),
                         visibility.clone(),
+                        exclude.is_some(),
                     ));
                 }
             }
@@ -235,11 +256,145 @@ type Instances = BTreeMap<
         GpuMesh,
         GpuStandardMaterial,
         ComputedVisibility,
+        ExtractedInstance,
     ),
 >;
 
 type AlisaTableCache = BTreeMap<Entity, (Vec3, Vec<GpuAliasEntry>)>;
 
+/// Points instances at the current regions of their meshes.
+///
+/// Mesh regions in the buffers may have moved, or been freed along with removed meshes
+/// and handed out to others. Instances of removed meshes are taken out and returned,
+/// to wait until their meshes are loaded again.
+fn refresh_instance_meshes(
+    collection: &mut Instances,
+    alias_table_cache: &mut AlisaTableCache,
+    meshes: &GpuMeshes,
+) -> Vec<ExtractedInstance> {
+    let mut unloaded = vec![];
+    for (entity, (instance, .., source)) in collection.iter_mut() {
+        match meshes.get(&source.3) {
+            Some((_, index)) => instance.mesh = *index,
+            None => unloaded.push(*entity),
+        }
+    }
+
+    unloaded
+        .into_iter()
+        .filter_map(|entity| {
+            alias_table_cache.remove(&entity);
+            collection.remove(&entity).map(|(.., source)| source)
+        })
+        .collect()
+}
+
+/// Builds the instance BVH, which leaves out instances excluded from GI, and the emissive BVH.
+fn build_instance_buffers(
+    render_assets: &mut InstanceRenderAssets,
+    collection: &mut Instances,
+    alias_table_cache: &mut AlisaTableCache,
+    excluded: &HashSet<Entity>,
+) {
+    let mut emissives = vec![];
+    let mut alias_table = vec![];
+
+    let mut instances: Vec<_> = collection
+        .values()
+        .map(|(instance, ..)| instance)
+        .cloned()
+        .collect();
+
+    // Instances excluded from GI stay in the instance buffer for the prepass,
+    // but are left out of the BVH, so leaf nodes must map back to their buffer indices.
+    let traced_ids: Vec<_> = collection
+        .keys()
+        .enumerate()
+        .filter(|(_, entity)| !excluded.contains(*entity))
+        .map(|(id, _)| id)
+        .collect();
+    let mut traced: Vec<_> = traced_ids.iter().map(|id| instances[*id].clone()).collect();
+    let instance_nodes = match traced.is_empty() {
+        true => vec![],
+        false => {
+            let bvh = BVH::build(&mut traced);
+            bvh.flatten_custom(&|aabb, entry_index, exit_index, primitive_index| {
+                let primitive_index = match entry_index {
+                    u32::MAX => traced_ids[primitive_index as usize] as u32,
+                    _ => primitive_index,
+                };
+                GpuNode::pack(aabb, entry_index, exit_index, primitive_index)
+            })
+        }
+    };
+    for (id, instance) in traced_ids.iter().zip_eq(traced) {
+        instances[*id] = instance;
+    }
+
+    for ((instance, ..), value) in collection.values_mut().zip_eq(instances.iter()) {
+        // Assign the computed BVH node index, and mesh/material indices.
+        *instance = value.clone();
+    }
+
+    for (id, (entity, (instance, mesh, material, ..))) in collection.iter().enumerate() {
+        let emissive = material.emissive;
+        let intensity = 255.0 * emissive.w * emissive.xyz().length();
+        if intensity > 0.0 && !excluded.contains(entity) {
+            // Compute alias table for light sampling
+            let instance_scale = instance.transform.to_scale_rotation_translation().0;
+            let alias_table = {
+                let cached_table = alias_table_cache.get(entity).and_then(|(scale, table)| {
+                    scale.abs_diff_eq(instance_scale, 0.01).then_some(table)
+                });
+                let cache_hit = cached_table.is_some();
+                let mut instance_table = cached_table
+                    .map_or_else(|| mesh.build_alias_table(instance.transform), Clone::clone);
+                if !cache_hit {
+                    alias_table_cache.insert(*entity, (instance_scale, instance_table.clone()));
+                }
+
+                let index = UVec2::new(alias_table.len() as u32, instance_table.len() as u32);
+                alias_table.append(&mut instance_table);
+                index
+            };
+
+            let surface_area = mesh
+                .transformed_primitive_areas(instance.transform)
+                .iter()
+                .sum();
+
+            // Add to emissive list.
+            let position = 0.5 * (instance.max + instance.min);
+            let radius = 0.5 * (instance.max - instance.min).length() + intensity.sqrt();
+            emissives.push(GpuEmissive {
+                emissive,
+                position,
+                radius,
+                instance: id as u32,
+                alias_table,
+                surface_area,
+                node_index: 0,
+            });
+        }
+    }
+
+    let emissive_nodes = match emissives.is_empty() {
+        true => vec![],
+        false => {
+            let bvh = BVH::build(&mut emissives);
+            bvh.flatten_custom(&GpuNode::pack)
+        }
+    };
+
+    render_assets.set(
+        instances,
+        instance_nodes,
+        emissives,
+        emissive_nodes,
+        alias_table,
+    );
+}
+
 /// Note: this system must run AFTER [`prepare_mesh_assets`].
 #[allow(clippy::too_many_arguments)]
 fn prepare_instances(
@@ -250,6 +405,7 @@ fn prepare_instances(
     mut extracted_instances: ResMut<ExtractedInstances>,
     mut collection: Local<Instances>,
     mut alias_table_cache: Local<AlisaTableCache>,
+    mut excluded: Local<HashSet<Entity>>,
     meshes: Res<GpuMeshes>,
     materials: Res<GpuStandardMaterials>,
     universal_settings: Res<HikariUniversalSettings>,
@@ -264,25 +420,30 @@ fn prepare_instances(
     for removed in extracted_instances.removed.drain(..) {
         collection.remove(&removed);
         alias_table_cache.remove(&removed);
+        excluded.remove(&removed);
     }
 
     let mut prepare_next_frame = vec![];
 
-    for (entity, aabb, transform, mesh, material, visibility) in extracted_instances
+    for (source, mesh, material) in extracted_instances
         .extracted
         .drain(..)
-        .filter_map(|(entity, aabb, transform, mesh, material, visibility)| {
-            match (meshes.get(&mesh), materials.get(&material)) {
-                (Some(mesh), Some(material)) => {
-                    Some((entity, aabb, transform, mesh, material, visibility))
-                }
+        .filter_map(
+            |source| match (meshes.get(&source.3), materials.get(&source.4)) {
+                (Some(gpu_mesh), Some(material)) => Some((source, gpu_mesh, material)),
                 _ => {
-                    prepare_next_frame.push((entity, aabb, transform, mesh, material, visibility));
+                    prepare_next_frame.push(source);
                     None
                 }
-            }
-        })
+            },
+        )
     {
+        let (entity, aabb, transform, _, _, visibility, exclude) = source.clone();
+        match exclude {
+            true => excluded.insert(entity),
+            false => excluded.remove(&entity),
+        };
+
         let transform = transform.compute_matrix();
         let center = transform.transform_point3a(aabb.center);
         let vertices: Vec<_> = (0..8i32)
@@ -323,6 +484,7 @@ fn prepare_instances(
                 mesh.0.clone(),
                 material.0.clone(),
                 visibility,
+                source,
             ),
         );
     }
@@ -332,12 +494,13 @@ fn prepare_instances(
         .append(&mut prepare_next_frame);
 
     // Since entities are cleared every frame, this should always be called.
-    let mut add_instance_indices = |instances: &Instances| {
+    let mut add_instance_indices = |render_assets: &mut InstanceRenderAssets,
+                                    instances: &Instances| {
         render_assets.instance_indices.clear();
         let command_batch: Vec<_> = instances
             .iter()
             .enumerate()
-            .map(|(id, (entity, (instance, _, _, _)))| {
+            .map(|(id, (entity, (instance, ..)))| {
                 let component = InstanceIndex {
                     instance: id as u32,
                     material: instance.material,
@@ -351,94 +514,154 @@ fn prepare_instances(
 
     if instance_changed || meshes.is_changed() || materials.is_changed() {
         // Important: update mesh and material info for every instance
-        let mut emissives = vec![];
-        let mut alias_table = vec![];
+        collection.retain(|_, (_, _, _, visibility, _)| visibility.is_visible_in_hierarchy());
 
-        collection.retain(|_, (_, _, _, visibility)| visibility.is_visible_in_hierarchy());
+        let mut unloaded =
+            refresh_instance_meshes(&mut collection, &mut alias_table_cache, &meshes);
+        extracted_instances.extracted.append(&mut unloaded);
 
-        let mut instances: Vec<_> = collection
-            .values()
-            .map(|(instance, _, _, _)| instance)
-            .cloned()
-            .collect();
+        build_instance_buffers(
+            &mut render_assets,
+            &mut collection,
+            &mut alias_table_cache,
+            &excluded,
+        );
+        add_instance_indices(&mut render_assets, &collection);
+        render_assets.write_buffer(&render_device, &render_queue);
+    } else {
+        add_instance_indices(&mut render_assets, &collection);
+        render_assets
+            .instance_indices
+            .write_buffer(&render_device, &render_queue);
+    }
+}
 
-        let instance_nodes = match collection.is_empty() {
-            true => vec![],
-            false => {
-                let bvh = BVH::build(&mut instances);
-                bvh.flatten_custom(&GpuNode::pack)
-            }
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh_material::{
+        mesh::tests::{handle, triangle, MeshAssetsRunner},
+        GpuMeshIndex,
+    };
+    use bevy::asset::HandleId;
 
-        for ((instance, _, _, _), value) in collection.values_mut().zip_eq(instances.iter()) {
-            // Assign the computed BVH node index, and mesh/material indices.
-            *instance = value.clone();
-        }
+    fn insert_instance(
+        collection: &mut Instances,
+        entity: Entity,
+        mesh: &Handle<Mesh>,
+        index: GpuMeshIndex,
+    ) {
+        let instance = GpuInstance {
+            mesh: index,
+            ..Default::default()
+        };
+        let source = (
+            entity,
+            Aabb::default(),
+            GlobalTransform::default(),
+            mesh.clone_weak(),
+            HandleUntyped::weak(HandleId::random::<StandardMaterial>()),
+            ComputedVisibility::default(),
+            false,
+        );
+        collection.insert(
+            entity,
+            (
+                instance,
+                GpuMesh::default(),
+                GpuStandardMaterial::default(),
+                ComputedVisibility::default(),
+                source,
+            ),
+        );
+    }
 
-        add_instance_indices(&collection);
-
-        for (id, (entity, (instance, mesh, material, _))) in collection.iter().enumerate() {
-            let emissive = material.emissive;
-            let intensity = 255.0 * emissive.w * emissive.xyz().length();
-            if intensity > 0.0 {
-                // Compute alias table for light sampling
-                let instance_scale = instance.transform.to_scale_rotation_translation().0;
-                let alias_table = {
-                    let cached_table = alias_table_cache.get(entity).and_then(|(scale, table)| {
-                        scale.abs_diff_eq(instance_scale, 0.01).then_some(table)
-                    });
-                    let cache_hit = cached_table.is_some();
-                    let mut instance_table = cached_table
-                        .map_or_else(|| mesh.build_alias_table(instance.transform), Clone::clone);
-                    if !cache_hit {
-                        alias_table_cache.insert(*entity, (instance_scale, instance_table.clone()));
-                    }
-
-                    let index = UVec2::new(alias_table.len() as u32, instance_table.len() as u32);
-                    alias_table.append(&mut instance_table);
-                    index
-                };
+    #[test]
+    fn recreated_mesh_requeues_instances() {
+        let mut runner = MeshAssetsRunner::new(default());
+        let (mesh, other) = (handle(), handle());
+        runner.run(vec![(mesh.clone(), triangle(0.0))], vec![]);
+        let old_index = runner.index(&mesh).unwrap();
+
+        let entity = Entity::from_raw(0);
+        let mut collection = Instances::default();
+        let mut alias_table_cache = AlisaTableCache::default();
+        insert_instance(&mut collection, entity, &mesh, old_index);
+        alias_table_cache.insert(entity, (Vec3::ONE, vec![]));
+
+        // The regions of the removed mesh are handed to another one in the same frame.
+        runner.run(vec![(other.clone(), triangle(1.0))], vec![mesh.clone()]);
+        assert_eq!(runner.index(&other).unwrap().vertex, old_index.vertex);
+
+        let requeued = refresh_instance_meshes(
+            &mut collection,
+            &mut alias_table_cache,
+            runner.world.resource(),
+        );
+        assert!(collection.is_empty());
+        assert!(alias_table_cache.is_empty());
+        assert_eq!(requeued.len(), 1);
+        assert_eq!(requeued[0].0, entity);
+        assert_eq!(requeued[0].3, mesh);
+
+        // Once re-created, the mesh lives in fresh regions.
+        runner.run(vec![(mesh.clone(), triangle(0.0))], vec![]);
+        let new_index = runner.index(&mesh).unwrap();
+        assert_ne!(new_index.vertex, old_index.vertex);
+
+        insert_instance(&mut collection, entity, &mesh, old_index);
+        let requeued = refresh_instance_meshes(
+            &mut collection,
+            &mut alias_table_cache,
+            runner.world.resource(),
+        );
+        assert!(requeued.is_empty());
+        assert_eq!(collection[&entity].0.mesh.vertex, new_index.vertex);
+        assert_eq!(collection[&entity].0.mesh.node, new_index.node);
+    }
 
-                let surface_area = mesh
-                    .transformed_primitive_areas(instance.transform)
-                    .iter()
-                    .sum();
-
-                // Add to emissive list.
-                let position = 0.5 * (instance.max + instance.min);
-                let radius = 0.5 * (instance.max - instance.min).length() + intensity.sqrt();
-                emissives.push(GpuEmissive {
-                    emissive,
-                    position,
-                    radius,
-                    instance: id as u32,
-                    alias_table,
-                    surface_area,
-                    node_index: 0,
-                });
-            }
+    #[test]
+    fn excluded_instances_are_not_traced() {
+        let mut collection = Instances::default();
+        let mut alias_table_cache = AlisaTableCache::default();
+        let entities = [0, 1, 2].map(Entity::from_raw);
+        for (entity, x) in entities.iter().zip([0.0, 2.0, 4.0]) {
+            insert_instance(&mut collection, *entity, &handle(), default());
+            let (instance, mesh, material, ..) = collection.get_mut(entity).unwrap();
+            instance.min = Vec3::new(x, 0.0, 0.0);
+            instance.max = Vec3::new(x + 1.0, 1.0, 0.0);
+            *mesh = GpuMesh::try_from(triangle(x)).unwrap();
+            material.emissive = Vec4::ONE;
         }
 
-        let emissive_nodes = match emissives.is_empty() {
-            true => vec![],
-            false => {
-                let bvh = BVH::build(&mut emissives);
-                bvh.flatten_custom(&GpuNode::pack)
-            }
-        };
-
-        render_assets.set(
-            instances,
-            instance_nodes,
-            emissives,
-            emissive_nodes,
-            alias_table,
+        let mut render_assets = InstanceRenderAssets::default();
+        let excluded = [entities[0]].into_iter().collect();
+        build_instance_buffers(
+            &mut render_assets,
+            &mut collection,
+            &mut alias_table_cache,
+            &excluded,
         );
-        render_assets.write_buffer(&render_device, &render_queue);
-    } else {
-        add_instance_indices(&collection);
-        render_assets
-            .instance_indices
-            .write_buffer(&render_device, &render_queue);
+
+        // The excluded instance is still drawn by the prepass.
+        assert_eq!(render_assets.instance_buffer.get().data.len(), 3);
+
+        // Leaves refer to indices in the instance buffer, past the excluded instance.
+        let mut leaves: Vec<_> = render_assets
+            .instance_node_buffer
+            .get()
+            .data
+            .iter()
+            .filter(|node| node.entry_index & 0x80000000 != 0)
+            .map(|node| node.entry_index & !0x80000000)
+            .collect();
+        leaves.sort();
+        assert_eq!(leaves, vec![1, 2]);
+
+        let emissives = &render_assets.emissive_buffer.get().data;
+        let mut lights: Vec<_> = emissives.iter().map(|emissive| emissive.instance).collect();
+        lights.sort();
+        assert_eq!(lights, vec![1, 2]);
+        assert!(!alias_table_cache.contains_key(&entities[0]));
     }
 }
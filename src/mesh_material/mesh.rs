@@ -1,66 +1,387 @@
 use crate::HikariUniversalSettings;
 
 use super::{
-    GpuMesh, GpuMeshIndex, GpuNode, GpuNodeBuffer, GpuPrimitive, GpuPrimitiveBuffer,
-    GpuPrimitiveCompact, GpuVertex, GpuVertexBuffer, GpuVertexCompact, MeshMaterialSystems,
+    GpuMesh, GpuMeshIndex, GpuNode, GpuNodeBuffer, GpuPrimitiveBuffer, GpuPrimitiveCompact,
+    GpuVertexBuffer, GpuVertexCompact, MeshMaterialSystems, PrepareMeshError,
 };
 use bevy::{
+    ecs::system::SystemParam,
     prelude::*,
     render::{
+        mesh::VertexAttributeValues,
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
         Extract, RenderApp, RenderStage,
     },
+    tasks::ComputeTaskPool,
     utils::{HashMap, HashSet},
 };
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    ops::Range,
+    sync::{Arc, RwLock},
+};
 
 pub struct MeshPlugin;
 impl Plugin for MeshPlugin {
     fn build(&self, app: &mut App) {
+        let mesh_indices = GpuMeshIndices::default();
+        let rebuild = MeshAssetsRebuild::default();
+        let stats = MeshAssetsStats::default();
+        app.add_event::<RebuildMeshAssets>()
+            .insert_resource(mesh_indices.clone())
+            .insert_resource(rebuild.clone())
+            .insert_resource(stats.clone());
+
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
             render_app
+                .insert_resource(mesh_indices)
+                .insert_resource(rebuild)
+                .insert_resource(stats)
                 .init_resource::<GpuMeshes>()
                 .init_resource::<MeshRenderAssets>()
                 .add_system_to_stage(RenderStage::Extract, extract_mesh_assets)
                 .add_system_to_stage(
                     RenderStage::Prepare,
-                    prepare_mesh_assets.label(MeshMaterialSystems::PrepareAssets),
+                    prepare_mesh_assets
+                        .pipe(upload_mesh_assets)
+                        .label(MeshMaterialSystems::PrepareAssets),
                 );
         }
     }
 }
 
+/// Mesh storage buffers are compacted when the ratio of free space exceeds this.
+pub const MAX_MESH_BUFFER_FRAGMENTATION: f32 = 0.5;
+
+/// Data of [`GpuNodeBuffer`] starts after `count`, padded to the alignment of [`GpuNode`].
+const NODE_BUFFER_DATA_OFFSET: u64 = 16;
+
+/// A first-fit allocator over a linear buffer, measured in elements.
+#[derive(Debug, Default, Clone)]
+pub struct FreeList {
+    /// Free regions in the form of `offset -> size`.
+    free: BTreeMap<u32, u32>,
+    /// End of the last allocated region.
+    len: u32,
+}
+
+impl FreeList {
+    /// Returns [`None`] if the buffer would outgrow the `u32` offset range.
+    pub fn allocate(&mut self, size: u32) -> Option<u32> {
+        let region = self
+            .free
+            .iter()
+            .find(|(_, free_size)| **free_size >= size)
+            .map(|(offset, free_size)| (*offset, *free_size));
+
+        match region {
+            Some((offset, free_size)) => {
+                self.free.remove(&offset);
+                if free_size > size {
+                    self.free.insert(offset + size, free_size - size);
+                }
+                Some(offset)
+            }
+            None => {
+                let offset = self.len;
+                self.len = self.len.checked_add(size)?;
+                Some(offset)
+            }
+        }
+    }
+
+    pub fn free(&mut self, mut offset: u32, mut size: u32) {
+        if size == 0 {
+            return;
+        }
+
+        // Merge with the succeeding free region.
+        if let Some(next_size) = self.free.remove(&(offset + size)) {
+            size += next_size;
+        }
+        // Merge with the preceding free region.
+        if let Some((&prev_offset, &prev_size)) = self.free.range(..offset).next_back() {
+            if prev_offset + prev_size == offset {
+                self.free.remove(&prev_offset);
+                offset = prev_offset;
+                size += prev_size;
+            }
+        }
+
+        if offset + size == self.len {
+            self.len = offset;
+        } else {
+            self.free.insert(offset, size);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.free.clear();
+        self.len = 0;
+    }
+
+    /// Length of the buffer region in use, including free holes.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Total size of free holes.
+    pub fn free_len(&self) -> u64 {
+        self.free.values().map(|&size| size as u64).sum()
+    }
+}
+
 /// Acceleration structures on GPU.
 #[derive(Default, Resource)]
 pub struct MeshRenderAssets {
     pub vertex_buffer: StorageBuffer<GpuVertexBuffer>,
     pub primitive_buffer: StorageBuffer<GpuPrimitiveBuffer>,
     pub node_buffer: StorageBuffer<GpuNodeBuffer>,
+
+    vertex_allocator: FreeList,
+    primitive_allocator: FreeList,
+    node_allocator: FreeList,
+
+    /// Ranges of vertices, primitives and nodes modified since the last upload.
+    dirty_vertices: Vec<Range<usize>>,
+    dirty_primitives: Vec<Range<usize>>,
+    dirty_nodes: Vec<Range<usize>>,
+    /// Element capacities of the buffers on GPU.
+    capacity: Option<(usize, usize, usize)>,
 }
 
 impl MeshRenderAssets {
-    pub fn set(
+    /// Allocates buffer regions for the mesh. Its data is not written until [`Self::write`].
+    pub fn allocate(&mut self, mesh: &GpuMesh) -> Result<GpuMeshIndex, PrepareMeshError> {
+        let count = |len: usize| u32::try_from(len).map_err(|_| PrepareMeshError::TooManyVertices);
+        let vertex_count = count(mesh.vertices.len())?;
+        let primitive_count = count(mesh.primitives.len())?;
+        let node_count = count(mesh.nodes.len())?;
+
+        let vertex = self.vertex_allocator.allocate(vertex_count);
+        let primitive = self.primitive_allocator.allocate(primitive_count);
+        let node = self.node_allocator.allocate(node_count);
+
+        match (vertex, primitive, node) {
+            (Some(vertex), Some(primitive), Some(node)) => Ok(GpuMeshIndex {
+                vertex: UVec2::new(vertex, vertex_count),
+                primitive: UVec2::new(primitive, primitive_count),
+                node: UVec2::new(node, node_count),
+            }),
+            _ => {
+                // Roll back the regions that did fit.
+                if let Some(vertex) = vertex {
+                    self.vertex_allocator.free(vertex, vertex_count);
+                }
+                if let Some(primitive) = primitive {
+                    self.primitive_allocator.free(primitive, primitive_count);
+                }
+                if let Some(node) = node {
+                    self.node_allocator.free(node, node_count);
+                }
+                Err(PrepareMeshError::TooManyVertices)
+            }
+        }
+    }
+
+    pub fn free(&mut self, index: &GpuMeshIndex) {
+        self.vertex_allocator.free(index.vertex.x, index.vertex.y);
+        self.primitive_allocator
+            .free(index.primitive.x, index.primitive.y);
+        self.node_allocator.free(index.node.x, index.node.y);
+    }
+
+    /// Reuses the regions of `old` if `mesh` fits in exactly, otherwise moves it elsewhere.
+    pub fn reallocate(
         &mut self,
-        vertices: Vec<GpuVertex>,
-        primitives: Vec<GpuPrimitive>,
-        nodes: Vec<GpuNode>,
-    ) {
-        self.vertex_buffer.get_mut().data =
-            vertices.into_iter().map(GpuVertexCompact::from).collect();
-        self.primitive_buffer.get_mut().data = primitives
-            .into_iter()
-            .map(GpuPrimitiveCompact::from)
-            .collect();
+        old: &GpuMesh,
+        index: GpuMeshIndex,
+        mesh: &GpuMesh,
+    ) -> Result<GpuMeshIndex, PrepareMeshError> {
+        if old.vertices.len() == mesh.vertices.len()
+            && old.primitives.len() == mesh.primitives.len()
+            && old.nodes.len() == mesh.nodes.len()
+        {
+            Ok(index)
+        } else {
+            self.free(&index);
+            self.allocate(mesh)
+        }
+    }
+
+    /// Total size of the buffers on GPU in bytes, including free holes.
+    pub fn gpu_bytes(&self) -> u64 {
+        [
+            self.vertex_buffer.buffer(),
+            self.primitive_buffer.buffer(),
+            self.node_buffer.buffer(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|buffer| buffer.size())
+        .sum()
+    }
+
+    /// Reserves capacity for at least the given numbers of additional elements in the buffers.
+    pub fn reserve(&mut self, vertices: usize, primitives: usize, nodes: usize) {
+        self.vertex_buffer.get_mut().data.reserve(vertices);
+        self.primitive_buffer.get_mut().data.reserve(primitives);
+        self.node_buffer.get_mut().data.reserve(nodes);
+    }
+
+    /// Releases all regions. Allocated meshes must be re-allocated and re-written.
+    pub fn clear(&mut self) {
+        self.vertex_allocator.clear();
+        self.primitive_allocator.clear();
+        self.node_allocator.clear();
+        self.dirty_vertices.clear();
+        self.dirty_primitives.clear();
+        self.dirty_nodes.clear();
+        self.capacity = None;
+    }
+
+    /// Ratio of free holes over the total length of the buffers.
+    pub fn fragmentation(&self) -> f32 {
+        let free = self.vertex_allocator.free_len()
+            + self.primitive_allocator.free_len()
+            + self.node_allocator.free_len();
+        let len = self.vertex_allocator.len() as u64
+            + self.primitive_allocator.len() as u64
+            + self.node_allocator.len() as u64;
+        match len {
+            0 => 0.0,
+            len => free as f32 / len as f32,
+        }
+    }
+
+    /// Copies the mesh data into its allocated regions.
+    pub fn write(&mut self, mesh: &GpuMesh, index: &GpuMeshIndex) {
+        let vertex = index.vertex.x as usize..(index.vertex.x + index.vertex.y) as usize;
+        let primitive =
+            index.primitive.x as usize..(index.primitive.x + index.primitive.y) as usize;
+        let node = index.node.x as usize..(index.node.x + index.node.y) as usize;
+
+        let vertices = &mut self.vertex_buffer.get_mut().data;
+        if vertices.len() < vertex.end {
+            vertices.resize(vertex.end, Default::default());
+        }
+        for (target, vertex) in vertices[vertex.clone()].iter_mut().zip(&mesh.vertices) {
+            *target = (*vertex).into();
+        }
+
+        let primitives = &mut self.primitive_buffer.get_mut().data;
+        if primitives.len() < primitive.end {
+            primitives.resize(primitive.end, Default::default());
+        }
+        for (target, primitive) in primitives[primitive.clone()]
+            .iter_mut()
+            .zip(&mesh.primitives)
+        {
+            *target = (*primitive).into();
+        }
+
+        let nodes = &mut self.node_buffer.get_mut().data;
+        if nodes.len() < node.end {
+            nodes.resize(node.end, Default::default());
+        }
+        nodes[node.clone()].copy_from_slice(&mesh.nodes);
+
+        self.dirty_vertices.push(vertex);
+        self.dirty_primitives.push(primitive);
+        self.dirty_nodes.push(node);
+    }
 
-        self.node_buffer.get_mut().count = nodes.len() as u32;
-        self.node_buffer.get_mut().data = nodes;
+    /// Trims or pads the buffer data to the allocated lengths.
+    pub fn fit_buffers(&mut self) {
+        let vertex_len = self.vertex_allocator.len() as usize;
+        let primitive_len = self.primitive_allocator.len() as usize;
+        let node_len = self.node_allocator.len() as usize;
+
+        // Keep at least one (unreferenced) element so that the buffers are never zero-sized.
+        let vertices = &mut self.vertex_buffer.get_mut().data;
+        vertices.resize(vertex_len.max(1), Default::default());
+        let primitives = &mut self.primitive_buffer.get_mut().data;
+        primitives.resize(primitive_len.max(1), Default::default());
+        let nodes = &mut self.node_buffer.get_mut().data;
+        nodes.resize(node_len.max(1), Default::default());
+        self.node_buffer.get_mut().count = node_len as u32;
     }
 
+    /// Uploads modified ranges, or the whole buffers if they have outgrown GPU capacities.
+    /// The buffers must have been fitted with [`Self::fit_buffers`] since the last change.
     pub fn write_buffer(&mut self, device: &RenderDevice, queue: &RenderQueue) {
-        self.vertex_buffer.write_buffer(device, queue);
-        self.primitive_buffer.write_buffer(device, queue);
-        self.node_buffer.write_buffer(device, queue);
+        let vertex_len = self.vertex_allocator.len() as usize;
+        let primitive_len = self.primitive_allocator.len() as usize;
+        let node_len = self.node_allocator.len() as usize;
+
+        let buffers = (
+            self.vertex_buffer.buffer(),
+            self.primitive_buffer.buffer(),
+            self.node_buffer.buffer(),
+        );
+        match (self.capacity, buffers) {
+            (Some(capacity), (Some(vertex_buffer), Some(primitive_buffer), Some(node_buffer)))
+                if vertex_len.max(1) <= capacity.0
+                    && primitive_len.max(1) <= capacity.1
+                    && node_len.max(1) <= capacity.2 =>
+            {
+                macro_rules! write_ranges {
+                    ($buffer:ident, $data:expr, $ty:ty, $offset:expr, $ranges:expr) => {
+                        let data = &$data;
+                        for range in $ranges.iter() {
+                            let range = range.start.min(data.len())..range.end.min(data.len());
+                            if range.is_empty() {
+                                continue;
+                            }
+                            let mut scratch = encase::StorageBuffer::new(Vec::<u8>::new());
+                            scratch.write(&data[range.clone()].to_vec()).unwrap();
+                            let offset = $offset + range.start as u64 * <$ty>::min_size().get();
+                            queue.write_buffer($buffer, offset, scratch.as_ref());
+                        }
+                    };
+                }
+
+                write_ranges!(
+                    vertex_buffer,
+                    self.vertex_buffer.get().data,
+                    GpuVertexCompact,
+                    0,
+                    self.dirty_vertices
+                );
+                write_ranges!(
+                    primitive_buffer,
+                    self.primitive_buffer.get().data,
+                    GpuPrimitiveCompact,
+                    0,
+                    self.dirty_primitives
+                );
+                write_ranges!(
+                    node_buffer,
+                    self.node_buffer.get().data,
+                    GpuNode,
+                    NODE_BUFFER_DATA_OFFSET,
+                    self.dirty_nodes
+                );
+
+                queue.write_buffer(node_buffer, 0, bytemuck::bytes_of(&(node_len as u32)));
+            }
+            _ => {
+                self.vertex_buffer.write_buffer(device, queue);
+                self.primitive_buffer.write_buffer(device, queue);
+                self.node_buffer.write_buffer(device, queue);
+                self.capacity = Some((vertex_len.max(1), primitive_len.max(1), node_len.max(1)));
+            }
+        }
+
+        self.dirty_vertices.clear();
+        self.dirty_primitives.clear();
+        self.dirty_nodes.clear();
     }
 }
 
@@ -68,16 +389,101 @@ impl MeshRenderAssets {
 #[derive(Default, Resource, Deref, DerefMut)]
 pub struct GpuMeshes(HashMap<Handle<Mesh>, (GpuMesh, GpuMeshIndex)>);
 
+/// Buffer offsets of loaded meshes, readable from the main world.
+/// Offsets are updated during rendering, so they may lag one frame behind the mesh assets.
+#[derive(Default, Clone, Resource)]
+pub struct GpuMeshIndices(Arc<RwLock<HashMap<Handle<Mesh>, GpuMeshIndex>>>);
+
+impl GpuMeshIndices {
+    pub fn get(&self, handle: &Handle<Mesh>) -> Option<GpuMeshIndex> {
+        self.0.read().unwrap().get(handle).copied()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MeshAssetsRebuildInfo {
+    /// Incremented on each upload.
+    pub generation: u64,
+    pub vertex_count: usize,
+    pub primitive_count: usize,
+    pub node_count: usize,
+}
+
+/// Updated in the render world every time the mesh buffers are uploaded.
+/// Also readable from the main world, where it may lag one frame behind the mesh assets.
+#[derive(Default, Clone, Resource)]
+pub struct MeshAssetsRebuild(Arc<RwLock<MeshAssetsRebuildInfo>>);
+
+impl MeshAssetsRebuild {
+    pub fn get(&self) -> MeshAssetsRebuildInfo {
+        *self.0.read().unwrap()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MeshStats {
+    pub vertex_count: usize,
+    pub primitive_count: usize,
+    pub node_count: usize,
+}
+
+#[derive(Debug, Default)]
+struct MeshAssetsStatsInner {
+    meshes: HashMap<Handle<Mesh>, MeshStats>,
+    total: MeshStats,
+    gpu_bytes: u64,
+}
+
+/// Sizes of the mesh acceleration structures, updated along with [`MeshAssetsRebuild`].
+/// Also readable from the main world, where it may lag one frame behind the mesh assets.
+#[derive(Default, Clone, Resource)]
+pub struct MeshAssetsStats(Arc<RwLock<MeshAssetsStatsInner>>);
+
+impl MeshAssetsStats {
+    pub fn get(&self, handle: &Handle<Mesh>) -> Option<MeshStats> {
+        self.0.read().unwrap().meshes.get(handle).copied()
+    }
+
+    pub fn meshes(&self) -> HashMap<Handle<Mesh>, MeshStats> {
+        self.0.read().unwrap().meshes.clone()
+    }
+
+    /// Sums of the counts over all meshes.
+    pub fn total(&self) -> MeshStats {
+        self.0.read().unwrap().total
+    }
+
+    /// Total size of the mesh buffers on GPU, including free holes.
+    pub fn gpu_bytes(&self) -> u64 {
+        self.0.read().unwrap().gpu_bytes
+    }
+}
+
+/// Send this event to discard all mesh acceleration structures and rebuild them from the assets.
+///
+/// Changes to mesh assets are picked up without this. Meshes modified while
+/// [`HikariUniversalSettings::build_mesh_acceleration_structure`] was off are not,
+/// so send this after turning it back on. Changes to
+/// [`HikariUniversalSettings::deduplicate_meshes`] only apply to meshes loaded or modified
+/// afterwards, so send this after changing it as well.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RebuildMeshAssets;
+
 #[derive(Default, Resource)]
 pub struct ExtractedMeshes {
     extracted: Vec<(Handle<Mesh>, Mesh)>,
     removed: Vec<Handle<Mesh>>,
+    rebuild: bool,
 }
 
 fn extract_mesh_assets(
     mut commands: Commands,
     mut events: Extract<EventReader<AssetEvent<Mesh>>>,
+    mut rebuild_events: Extract<EventReader<RebuildMeshAssets>>,
     assets: Extract<Res<Assets<Mesh>>>,
+    instances: Extract<Query<&Handle<Mesh>>>,
+    universal_settings: Extract<Res<HikariUniversalSettings>>,
+    mut used: Local<HashSet<Handle<Mesh>>>,
 ) {
     let mut changed_assets = HashSet::default();
     let mut removed = Vec::new();
@@ -93,6 +499,27 @@ fn extract_mesh_assets(
         }
     }
 
+    let rebuild = rebuild_events.iter().count() > 0;
+    if universal_settings.extract_all_meshes {
+        used.clear();
+        if rebuild {
+            changed_assets.extend(assets.ids().map(Handle::weak));
+        }
+    } else {
+        let current: HashSet<_> = instances.iter().map(Handle::clone_weak).collect();
+
+        // Unload meshes no longer used by any entity, and load newly used ones.
+        removed.extend(used.difference(&current).cloned());
+        changed_assets.retain(|handle| current.contains(handle));
+        if rebuild {
+            changed_assets.extend(current.iter().cloned());
+        } else {
+            changed_assets.extend(current.difference(&used).cloned());
+        }
+
+        *used = current;
+    }
+
     let mut extracted = Vec::new();
     for handle in changed_assets.drain() {
         if let Some(mesh) = assets.get(&handle) {
@@ -100,67 +527,517 @@ fn extract_mesh_assets(
         }
     }
 
-    commands.insert_resource(ExtractedMeshes { extracted, removed });
+    commands.insert_resource(ExtractedMeshes {
+        extracted,
+        removed,
+        rebuild,
+    });
+}
+
+/// Meshes sharing each buffer region, keyed by vertex offset.
+type RegionUsers = HashMap<u32, HashSet<Handle<Mesh>>>;
+
+/// Content hashes of deduplicated meshes, and one mesh holding the regions of each hash.
+#[derive(Default)]
+struct MeshHashes {
+    hashes: HashMap<Handle<Mesh>, u64>,
+    owners: HashMap<u64, Handle<Mesh>>,
+}
+
+impl MeshHashes {
+    fn insert(&mut self, handle: &Handle<Mesh>, hash: u64) {
+        self.hashes.insert(handle.clone_weak(), hash);
+        self.owners.insert(hash, handle.clone_weak());
+    }
+
+    /// If `handle` holds the regions of its hash, they are handed to one of the remaining `users`.
+    fn remove(&mut self, handle: &Handle<Mesh>, users: Option<&HashSet<Handle<Mesh>>>) {
+        if let Some(hash) = self.hashes.remove(handle) {
+            if self.owners.get(&hash) == Some(handle) {
+                match users.and_then(|users| users.iter().next()) {
+                    Some(user) => self.insert(user, hash),
+                    None => {
+                        self.owners.remove(&hash);
+                    }
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.hashes.clear();
+        self.owners.clear();
+    }
+}
+
+/// Drops `handle` from the users of the regions of `index`, returning whether they are no longer used.
+fn release(users: &mut RegionUsers, index: &GpuMeshIndex, handle: &Handle<Mesh>) -> bool {
+    match users.get_mut(&index.vertex.x) {
+        Some(region_users) => {
+            region_users.remove(handle);
+            if region_users.is_empty() {
+                users.remove(&index.vertex.x);
+                true
+            } else {
+                false
+            }
+        }
+        None => true,
+    }
+}
+
+/// Bookkeeping of [`prepare_mesh_assets`] kept across frames.
+#[derive(Default)]
+struct MeshAssetsState {
+    /// Meshes that failed to load, so that each is warned about once.
+    failed: HashSet<Handle<Mesh>>,
+    /// Meshes without normals, so that each is warned about once.
+    flat_shaded: HashSet<Handle<Mesh>>,
+    hashes: MeshHashes,
+    users: RegionUsers,
+}
+
+impl MeshAssetsState {
+    fn clear(&mut self) {
+        self.failed.clear();
+        self.flat_shaded.clear();
+        self.hashes.clear();
+        self.users.clear();
+    }
+}
+
+/// Resources shared with the main world.
+#[derive(SystemParam)]
+struct SharedMeshAssets<'w, 's> {
+    rebuild: Res<'w, MeshAssetsRebuild>,
+    stats: Res<'w, MeshAssetsStats>,
+    mesh_indices: Res<'w, GpuMeshIndices>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
 }
 
+impl SharedMeshAssets<'_, '_> {
+    fn update(&self, meshes: &GpuMeshes) {
+        let mesh_stats: HashMap<_, _> = meshes
+            .iter()
+            .map(|(handle, (mesh, _))| {
+                let counts = MeshStats {
+                    vertex_count: mesh.vertices.len(),
+                    primitive_count: mesh.primitives.len(),
+                    node_count: mesh.nodes.len(),
+                };
+                (handle.clone_weak(), counts)
+            })
+            .collect();
+        let total = mesh_stats
+            .values()
+            .fold(MeshStats::default(), |total, mesh| MeshStats {
+                vertex_count: total.vertex_count + mesh.vertex_count,
+                primitive_count: total.primitive_count + mesh.primitive_count,
+                node_count: total.node_count + mesh.node_count,
+            });
+
+        {
+            let mut rebuild = self.rebuild.0.write().unwrap();
+            rebuild.generation += 1;
+            rebuild.vertex_count = total.vertex_count;
+            rebuild.primitive_count = total.primitive_count;
+            rebuild.node_count = total.node_count;
+        }
+        {
+            let mut stats = self.stats.0.write().unwrap();
+            stats.meshes = mesh_stats;
+            stats.total = total;
+        }
+
+        *self.mesh_indices.0.write().unwrap() = meshes
+            .iter()
+            .map(|(handle, (_, index))| (handle.clone_weak(), *index))
+            .collect();
+    }
+}
+
+/// Returns whether the mesh buffers need to be uploaded.
 fn prepare_mesh_assets(
     mut extracted_assets: ResMut<ExtractedMeshes>,
-    mut assets: Local<BTreeMap<Handle<Mesh>, GpuMesh>>,
     mut meshes: ResMut<GpuMeshes>,
     mut render_assets: ResMut<MeshRenderAssets>,
-    render_device: Res<RenderDevice>,
-    render_queue: Res<RenderQueue>,
+    mut state: Local<MeshAssetsState>,
+    shared: SharedMeshAssets,
     universal_settings: Res<HikariUniversalSettings>,
-) {
+) -> bool {
     if !universal_settings.build_mesh_acceleration_structure {
-        return;
+        return false;
+    }
+
+    if extracted_assets.removed.is_empty()
+        && extracted_assets.extracted.is_empty()
+        && !extracted_assets.rebuild
+    {
+        return false;
     }
 
-    if extracted_assets.removed.is_empty() && extracted_assets.extracted.is_empty() {
-        return;
+    if extracted_assets.rebuild {
+        meshes.clear();
+        render_assets.clear();
+        state.clear();
     }
 
+    let MeshAssetsState {
+        failed,
+        flat_shaded,
+        hashes,
+        users,
+    } = &mut *state;
+
+    let any_removed = extracted_assets.rebuild || !extracted_assets.removed.is_empty();
     for handle in extracted_assets.removed.drain(..) {
-        assets.remove(&handle);
-        meshes.remove(&handle);
+        if let Some((_, index)) = meshes.remove(&handle) {
+            if release(users, &index, &handle) {
+                render_assets.free(&index);
+            }
+            hashes.remove(&handle, users.get(&index.vertex.x));
+        }
+        failed.remove(&handle);
+        flat_shaded.remove(&handle);
+    }
+
+    // Build BVHs in parallel, then insert them in a deterministic order.
+    let mut converted: Vec<_> = {
+        let meshes = &*meshes;
+        ComputeTaskPool::get().scope(|scope| {
+            for (handle, mesh) in extracted_assets.extracted.drain(..) {
+                let old = meshes.get(&handle).map(|(old, _)| old);
+                scope.spawn(async move {
+                    let flat = mesh
+                        .attribute(Mesh::ATTRIBUTE_NORMAL)
+                        .and_then(VertexAttributeValues::as_float3)
+                        .is_none();
+                    let mesh = match old {
+                        Some(old) => old.try_update(&mesh).transpose(),
+                        None => Some(GpuMesh::try_from(mesh)),
+                    };
+                    mesh.map(|mesh| (handle, mesh, flat))
+                });
+            }
+        })
+    }
+    .into_iter()
+    .flatten()
+    .collect();
+    converted.sort_by(|(lhs, _, _), (rhs, _, _)| lhs.cmp(rhs));
+
+    // Modified events that left the mesh data unchanged need no upload.
+    if !any_removed && converted.is_empty() {
+        return false;
+    }
+
+    let (vertices, primitives, nodes) = converted
+        .iter()
+        .filter_map(|(_, mesh, _)| mesh.as_ref().ok())
+        .fold((0, 0, 0), |(vertices, primitives, nodes), mesh| {
+            (
+                vertices + mesh.vertices.len(),
+                primitives + mesh.primitives.len(),
+                nodes + mesh.nodes.len(),
+            )
+        });
+    render_assets.reserve(vertices, primitives, nodes);
+
+    for (handle, mesh, flat) in converted {
+        let mesh = mesh.and_then(|mesh| {
+            // Regions shared with other meshes must be left untouched.
+            let old = meshes.remove(&handle).filter(|(_, index)| {
+                let unused = release(users, index, &handle);
+                hashes.remove(&handle, users.get(&index.vertex.x));
+                unused
+            });
+
+            let hash = universal_settings
+                .deduplicate_meshes
+                .then(|| mesh.content_hash());
+            // Hashes may collide, so only share regions with meshes holding the same data.
+            let duplicate = hash
+                .and_then(|hash| hashes.owners.get(&hash))
+                .and_then(|other| meshes.get(other))
+                .filter(|(other, _)| other.same_data(&mesh))
+                .map(|(_, index)| *index);
+
+            let (index, write) = match (duplicate, old) {
+                (Some(index), old) => {
+                    if let Some((_, old_index)) = old {
+                        render_assets.free(&old_index);
+                    }
+                    (index, false)
+                }
+                (None, Some((old, index))) => (render_assets.reallocate(&old, index, &mesh)?, true),
+                (None, None) => (render_assets.allocate(&mesh)?, true),
+            };
+            users
+                .entry(index.vertex.x)
+                .or_default()
+                .insert(handle.clone_weak());
+
+            if let (Some(hash), None) = (hash, duplicate) {
+                hashes.insert(&handle, hash);
+            }
+            Ok((mesh, index, write))
+        });
+        match mesh {
+            Ok((mesh, index, write)) => {
+                if write {
+                    render_assets.write(&mesh, &index);
+                }
+
+                info!("Loaded mesh {}", meshes.len());
+                failed.remove(&handle);
+                if !flat {
+                    flat_shaded.remove(&handle);
+                } else if flat_shaded.insert(handle.clone_weak()) {
+                    warn!(
+                        "Mesh {:?} has no normals, falling back to flat normals",
+                        handle
+                    );
+                }
+                meshes.insert(handle, (mesh, index));
+            }
+            Err(err) => {
+                // Warn once per mesh, or on every failure with `warn_mesh_load`.
+                if cfg!(feature = "warn_mesh_load") || failed.insert(handle.clone_weak()) {
+                    warn!("Skipping mesh {:?}: {:?}", handle, err);
+                }
+            }
+        }
     }
-    for (handle, mesh) in extracted_assets.extracted.drain(..) {
-        match mesh.try_into() {
-            Ok(mesh) => {
-                info!("Loaded mesh {}", assets.len());
-                assets.insert(handle, mesh);
+
+    if render_assets.fragmentation() > MAX_MESH_BUFFER_FRAGMENTATION {
+        // Too many holes: re-pack all meshes from scratch.
+        render_assets.clear();
+        let mut moved = HashMap::<u32, GpuMeshIndex>::default();
+        meshes.retain(|_, (mesh, index)| {
+            if let Some(new_index) = moved.get(&index.vertex.x) {
+                // Already moved along with a mesh it shares regions with.
+                *index = *new_index;
+                return true;
             }
-            Err(_err) => {
-                #[cfg(feature = "warn_mesh_load")]
-                warn!("Encounter an error when loading mesh: {:#?}", _err);
+            match render_assets.allocate(mesh) {
+                Ok(new_index) => {
+                    moved.insert(index.vertex.x, new_index);
+                    *index = new_index;
+                    render_assets.write(mesh, index);
+                    true
+                }
+                Err(_) => false,
             }
+        });
+
+        users.clear();
+        for (handle, (_, index)) in meshes.iter() {
+            users
+                .entry(index.vertex.x)
+                .or_default()
+                .insert(handle.clone_weak());
         }
     }
 
-    let mut vertices = vec![];
-    let mut primitives = vec![];
-    let mut nodes = vec![];
+    render_assets.fit_buffers();
+    shared.update(&meshes);
+    true
+}
+
+fn upload_mesh_assets(
+    In(upload): In<bool>,
+    mut render_assets: ResMut<MeshRenderAssets>,
+    stats: Res<MeshAssetsStats>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if upload {
+        render_assets.write_buffer(&render_device, &render_queue);
+        stats.0.write().unwrap().gpu_bytes = render_assets.gpu_bytes();
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use bevy::{
+        asset::HandleId,
+        ecs::system::BoxedSystem,
+        render::{mesh::Indices, render_resource::PrimitiveTopology},
+        tasks::TaskPool,
+    };
 
-    for (handle, mesh) in assets.iter() {
-        let vertex = vertices.len() as u32;
-        let primitive = primitives.len() as u32;
-        let node = UVec2::new(nodes.len() as u32, mesh.nodes.len() as u32);
+    /// Runs [`prepare_mesh_assets`] frame by frame, without a render device.
+    pub struct MeshAssetsRunner {
+        pub world: World,
+        system: BoxedSystem<(), bool>,
+    }
 
-        {
-            let mut mesh = mesh.clone();
-            vertices.append(&mut mesh.vertices);
-            primitives.append(&mut mesh.primitives);
-            nodes.append(&mut mesh.nodes);
+    impl MeshAssetsRunner {
+        pub fn new(universal_settings: HikariUniversalSettings) -> Self {
+            ComputeTaskPool::init(TaskPool::default);
+
+            let mut world = World::new();
+            world.insert_resource(universal_settings);
+            world.init_resource::<ExtractedMeshes>();
+            world.init_resource::<GpuMeshes>();
+            world.init_resource::<MeshRenderAssets>();
+            world.init_resource::<MeshAssetsRebuild>();
+            world.init_resource::<MeshAssetsStats>();
+            world.init_resource::<GpuMeshIndices>();
+
+            let mut system: BoxedSystem<(), bool> =
+                Box::new(IntoSystem::into_system(prepare_mesh_assets));
+            system.initialize(&mut world);
+            Self { world, system }
+        }
+
+        /// Returns whether the buffers would be uploaded.
+        pub fn run(
+            &mut self,
+            extracted: Vec<(Handle<Mesh>, Mesh)>,
+            removed: Vec<Handle<Mesh>>,
+        ) -> bool {
+            self.world.insert_resource(ExtractedMeshes {
+                extracted,
+                removed,
+                rebuild: false,
+            });
+            self.system.run((), &mut self.world)
+        }
+
+        pub fn index(&self, handle: &Handle<Mesh>) -> Option<GpuMeshIndex> {
+            let meshes = self.world.resource::<GpuMeshes>();
+            meshes.get(handle).map(|(_, index)| *index)
         }
+    }
+
+    pub fn handle() -> Handle<Mesh> {
+        Handle::weak(HandleId::random::<Mesh>())
+    }
+
+    pub fn triangle(x: f32) -> Mesh {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        let positions = vec![[x, 0.0, 0.0], [x + 1.0, 0.0, 0.0], [x, 1.0, 0.0]];
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, 1.0]; 3]);
+        mesh.set_indices(Some(Indices::U16(vec![0, 1, 2])));
+        mesh
+    }
+
+    #[test]
+    fn allocate_first_fit() {
+        let mut list = FreeList::default();
+        assert_eq!(list.allocate(4), Some(0));
+        assert_eq!(list.allocate(4), Some(4));
+        assert_eq!(list.allocate(4), Some(8));
+        assert_eq!(list.len(), 12);
+
+        list.free(4, 4);
+        assert_eq!(list.free_len(), 4);
+        assert_eq!(list.allocate(2), Some(4));
+        assert_eq!(list.free_len(), 2);
+        assert_eq!(list.allocate(8), Some(12));
+        assert_eq!(list.len(), 20);
+    }
+
+    #[test]
+    fn free_merges_neighbours() {
+        let mut list = FreeList::default();
+        let regions = [4, 2, 2, 4].map(|size| (list.allocate(size).unwrap(), size));
+        assert_eq!(list.len(), 12);
+
+        list.free(regions[0].0, regions[0].1);
+        list.free(regions[2].0, regions[2].1);
+        assert_eq!(list.free_len(), 6);
+        // Merges with both the preceding and the succeeding holes.
+        list.free(regions[1].0, regions[1].1);
+        assert_eq!(list.free_len(), 8);
+        assert_eq!(list.allocate(8), Some(0));
+
+        list.free(0, 8);
+        // Trailing holes shrink the list.
+        list.free(regions[3].0, regions[3].1);
+        assert!(list.is_empty());
+        assert_eq!(list.free_len(), 0);
+    }
+
+    #[test]
+    fn allocate_out_of_range() {
+        let mut list = FreeList::default();
+        assert_eq!(list.allocate(u32::MAX), Some(0));
+        assert_eq!(list.allocate(1), None);
+        assert_eq!(list.len(), u32::MAX);
+    }
+
+    #[test]
+    fn remove_last_mesh() {
+        let mut runner = MeshAssetsRunner::new(default());
+        let mesh = handle();
+        assert!(runner.run(vec![(mesh.clone(), triangle(0.0))], vec![]));
+        let rebuild = runner.world.resource::<MeshAssetsRebuild>().get();
+        assert_eq!(rebuild.vertex_count, 3);
+        assert_eq!(rebuild.primitive_count, 1);
+
+        assert!(runner.run(vec![], vec![mesh]));
+        // Buffers keep a placeholder element, but hold no nodes to traverse.
+        let render_assets = runner.world.resource::<MeshRenderAssets>();
+        assert_eq!(render_assets.vertex_buffer.get().data.len(), 1);
+        assert_eq!(render_assets.primitive_buffer.get().data.len(), 1);
+        assert_eq!(render_assets.node_buffer.get().data.len(), 1);
+        assert_eq!(render_assets.node_buffer.get().count, 0);
+
+        let rebuild = runner.world.resource::<MeshAssetsRebuild>().get();
+        assert_eq!(rebuild.generation, 2);
+        assert_eq!(rebuild.vertex_count, 0);
+        assert_eq!(rebuild.primitive_count, 0);
+        assert_eq!(rebuild.node_count, 0);
+        assert!(runner.world.resource::<GpuMeshes>().is_empty());
+    }
+
+    #[test]
+    fn removed_owner_hands_over_shared_regions() {
+        let mut runner = MeshAssetsRunner::new(HikariUniversalSettings {
+            deduplicate_meshes: true,
+            ..default()
+        });
+        let [first, second, third] = [(); 3].map(|_| handle());
+        runner.run(vec![(first.clone(), triangle(0.0))], vec![]);
+        runner.run(vec![(second.clone(), triangle(0.0))], vec![]);
+        let index = runner.index(&first).unwrap();
+        assert_eq!(runner.index(&second).unwrap().vertex, index.vertex);
+
+        // The remaining mesh keeps the regions, and shares them with new duplicates.
+        runner.run(vec![(third.clone(), triangle(0.0))], vec![first]);
+        assert_eq!(runner.index(&second).unwrap().vertex, index.vertex);
+        assert_eq!(runner.index(&third).unwrap().vertex, index.vertex);
+
+        // The regions are freed along with their last user.
+        runner.run(vec![], vec![second, third]);
+        let render_assets = runner.world.resource::<MeshRenderAssets>();
+        assert!(render_assets.vertex_allocator.is_empty());
+        assert!(render_assets.node_allocator.is_empty());
+    }
+
+    #[test]
+    fn unchanged_mesh_keeps_generation() {
+        let mut runner = MeshAssetsRunner::new(default());
+        let mesh = handle();
+        assert!(runner.run(vec![(mesh.clone(), triangle(0.0))], vec![]));
+        let generation = runner
+            .world
+            .resource::<MeshAssetsRebuild>()
+            .get()
+            .generation;
 
-        let index = GpuMeshIndex {
-            vertex,
-            primitive,
-            node,
-        };
+        // A modified event with identical data.
+        assert!(!runner.run(vec![(mesh.clone(), triangle(0.0))], vec![]));
+        let rebuild = runner.world.resource::<MeshAssetsRebuild>().get();
+        assert_eq!(rebuild.generation, generation);
 
-        meshes.insert(handle.clone_weak(), (mesh.clone(), index));
+        assert!(runner.run(vec![(mesh, triangle(1.0))], vec![]));
+        let rebuild = runner.world.resource::<MeshAssetsRebuild>().get();
+        assert_eq!(rebuild.generation, generation + 1);
     }
-    render_assets.set(vertices, primitives, nodes);
-    render_assets.write_buffer(&render_device, &render_queue);
 }
@@ -22,18 +22,25 @@ use bvh::{
     bvh::BVH,
 };
 use itertools::Itertools;
-use std::num::NonZeroU32;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    num::NonZeroU32,
+};
 
 pub mod instance;
 pub mod material;
 pub mod mesh;
 
 pub use instance::{
-    DynamicInstanceIndex, GenericInstancePlugin, InstanceIndex, InstanceRenderAssets,
-    PreviousMeshUniform,
+    DynamicInstanceIndex, ExcludeFromGi, GenericInstancePlugin, InstanceIndex,
+    InstanceRenderAssets, PreviousMeshUniform,
 };
 pub use material::{GenericMaterialPlugin, MaterialRenderAssets};
-pub use mesh::MeshRenderAssets;
+pub use mesh::{
+    GpuMeshIndices, MeshAssetsRebuild, MeshAssetsRebuildInfo, MeshAssetsStats, MeshRenderAssets,
+    MeshStats, RebuildMeshAssets,
+};
 
 pub struct MeshMaterialPlugin;
 impl Plugin for MeshMaterialPlugin {
@@ -45,6 +52,12 @@ impl Plugin for MeshMaterialPlugin {
             .add_plugin(GenericInstancePlugin::<StandardMaterial>::default());
 
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            // The dummy texture of `MeshPipeline` stands in for missing material textures.
+            assert!(
+                render_app.world.contains_resource::<MeshPipeline>(),
+                "`MeshPipeline` not found: add `HikariPlugin` after `PbrPlugin`"
+            );
+
             render_app
                 .init_resource::<MeshMaterialBindGroupLayout>()
                 .init_resource::<TextureBindGroupLayout>()
@@ -57,11 +70,15 @@ impl Plugin for MeshMaterialPlugin {
     }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct GpuVertex {
     pub position: Vec3,
     pub normal: Vec3,
     pub uv: Vec2,
+    /// Zero if the mesh has no tangents.
+    pub tangent: Vec4,
+    /// White if the mesh has no vertex colors.
+    pub color: Vec4,
 }
 
 #[derive(Debug, Default, Clone, Copy, ShaderType)]
@@ -70,6 +87,8 @@ pub struct GpuVertexCompact {
     pub u: f32,
     pub normal: Vec3,
     pub v: f32,
+    pub tangent: Vec4,
+    pub color: Vec4,
 }
 
 impl From<GpuVertex> for GpuVertexCompact {
@@ -79,6 +98,8 @@ impl From<GpuVertex> for GpuVertexCompact {
             normal: vertex.normal,
             u: vertex.uv.x,
             v: vertex.uv.y,
+            tangent: vertex.tangent,
+            color: vertex.color,
         }
     }
 }
@@ -301,10 +322,33 @@ pub struct GpuEmissiveBuffer {
 #[derive(Debug)]
 pub enum PrepareMeshError {
     MissingAttributePosition,
-    MissingAttributeNormal,
-    MissingAttributeUV,
-    IncompatiblePrimitiveTopology,
+    /// Positions must be [`VertexFormat::Float32x3`] or [`VertexFormat::Float32x4`].
+    IncompatibleAttributePosition(VertexFormat),
+    IncompatibleAttributeUV,
+    /// The topology is neither a triangle list nor a triangle strip,
+    /// or a triangle list ends with an incomplete triangle.
+    IncompatiblePrimitiveTopology(PrimitiveTopology),
     NoPrimitive,
+    MismatchedAttributeLengths {
+        positions: usize,
+        normals: Option<usize>,
+        uvs: Option<usize>,
+        tangents: Option<usize>,
+        colors: Option<usize>,
+    },
+    IndexOutOfRange {
+        index: usize,
+        vertex_count: usize,
+    },
+    /// The mesh does not fit in the `u32` index range of the mesh buffers.
+    TooManyVertices,
+}
+
+#[derive(Debug)]
+pub enum BvhValidationError {
+    EntryIndexOutOfRange { node: u32, entry_index: u32 },
+    ExitIndexOutOfRange { node: u32, exit_index: u32 },
+    PrimitiveIndexOutOfRange { node: u32, primitive_index: u32 },
 }
 
 #[derive(Default, Clone)]
@@ -312,6 +356,9 @@ pub struct GpuMesh {
     pub vertices: Vec<GpuVertex>,
     pub primitives: Vec<GpuPrimitive>,
     pub nodes: Vec<GpuNode>,
+    /// Hash of the primitive indices into the source mesh.
+    /// Primitive indices alone don't tell if flat shaded meshes were re-indexed.
+    source_index_hash: u64,
 }
 
 impl GpuMesh {
@@ -376,32 +423,86 @@ impl GpuMesh {
     }
 }
 
-impl TryFrom<Mesh> for GpuMesh {
-    type Error = PrepareMeshError;
-
-    fn try_from(mesh: Mesh) -> Result<Self, Self::Error> {
-        let positions = mesh
-            .attribute(Mesh::ATTRIBUTE_POSITION)
-            .and_then(VertexAttributeValues::as_float3)
-            .ok_or(PrepareMeshError::MissingAttributePosition)?;
+impl GpuMesh {
+    /// Decodes vertices and primitives of the mesh, leaving the BVH empty.
+    fn decode(mesh: &Mesh) -> Result<Self, PrepareMeshError> {
+        let positions: Vec<_> = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(values)) => {
+                values.iter().map(|&value| Vec3::from(value)).collect()
+            }
+            Some(VertexAttributeValues::Float32x4(values)) => values
+                .iter()
+                .map(|&value| Vec4::from(value).truncate())
+                .collect(),
+            Some(values) => {
+                return Err(PrepareMeshError::IncompatibleAttributePosition(
+                    values.into(),
+                ))
+            }
+            None => return Err(PrepareMeshError::MissingAttributePosition),
+        };
+        if u32::try_from(positions.len()).is_err() {
+            return Err(PrepareMeshError::TooManyVertices);
+        }
         let normals = mesh
             .attribute(Mesh::ATTRIBUTE_NORMAL)
-            .and_then(VertexAttributeValues::as_float3)
-            .ok_or(PrepareMeshError::MissingAttributeNormal)?;
-        let uvs = mesh
-            .attribute(Mesh::ATTRIBUTE_UV_0)
+            .and_then(VertexAttributeValues::as_float3);
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(value)) => Some(value),
+            Some(_) => return Err(PrepareMeshError::IncompatibleAttributeUV),
+            None => None,
+        };
+        let tangents =
+            mesh.attribute(Mesh::ATTRIBUTE_TANGENT)
+                .and_then(|attribute| match attribute {
+                    VertexAttributeValues::Float32x4(value) => Some(value),
+                    _ => None,
+                });
+        let colors = mesh
+            .attribute(Mesh::ATTRIBUTE_COLOR)
             .and_then(|attribute| match attribute {
-                VertexAttributeValues::Float32x2(value) => Some(value),
+                VertexAttributeValues::Float32x4(value) => Some(value),
                 _ => None,
-            })
-            .ok_or(PrepareMeshError::MissingAttributeUV)?;
+            });
+
+        let normal_count = normals.map(|normals| normals.len());
+        let uv_count = uvs.map(|uvs| uvs.len());
+        let tangent_count = tangents.map(|tangents| tangents.len());
+        let color_count = colors.map(|colors| colors.len());
+        if [normal_count, uv_count, tangent_count, color_count]
+            .into_iter()
+            .flatten()
+            .any(|count| count != positions.len())
+        {
+            return Err(PrepareMeshError::MismatchedAttributeLengths {
+                positions: positions.len(),
+                normals: normal_count,
+                uvs: uv_count,
+                tangents: tangent_count,
+                colors: color_count,
+            });
+        }
 
         let mut vertices = vec![];
-        for (position, normal, uv) in itertools::multizip((positions, normals, uvs)) {
+        for (id, position) in positions.iter().enumerate() {
+            let uv = uvs
+                .and_then(|uvs| uvs.get(id))
+                .map_or(Vec2::ZERO, |uv| Vec2::from_slice(uv));
+            let normal = normals
+                .and_then(|normals| normals.get(id))
+                .map_or(Vec3::ZERO, |normal| Vec3::from_slice(normal));
+            let tangent = tangents
+                .and_then(|tangents| tangents.get(id))
+                .map_or(Vec4::ZERO, |tangent| Vec4::from_array(*tangent));
+            let color = colors
+                .and_then(|colors| colors.get(id))
+                .map_or(Vec4::ONE, |color| Vec4::from_array(*color));
             vertices.push(GpuVertex {
-                position: Vec3::from_slice(position),
-                normal: Vec3::from_slice(normal),
-                uv: Vec2::from_slice(uv),
+                position: *position,
+                normal,
+                uv,
+                tangent,
+                color,
             });
         }
 
@@ -409,15 +510,22 @@ impl TryFrom<Mesh> for GpuMesh {
             Some(indices) => indices.iter().collect(),
             None => vertices.iter().enumerate().map(|(id, _)| id).collect(),
         };
+        if let Some(&index) = indices.iter().find(|&&index| index >= vertices.len()) {
+            return Err(PrepareMeshError::IndexOutOfRange {
+                index,
+                vertex_count: vertices.len(),
+            });
+        }
 
         let mut primitives = match mesh.primitive_topology() {
             PrimitiveTopology::TriangleList => {
                 let mut primitives = vec![];
                 for chunk in &indices.iter().chunks(3) {
-                    let (v0, v1, v2) = chunk
-                        .cloned()
-                        .next_tuple()
-                        .ok_or(PrepareMeshError::IncompatiblePrimitiveTopology)?;
+                    let (v0, v1, v2) = chunk.cloned().next_tuple().ok_or(
+                        PrepareMeshError::IncompatiblePrimitiveTopology(
+                            PrimitiveTopology::TriangleList,
+                        ),
+                    )?;
                     let vertices = [v0, v1, v2]
                         .map(|id| vertices[id])
                         .map(|vertex| vertex.position);
@@ -433,6 +541,10 @@ impl TryFrom<Mesh> for GpuMesh {
             PrimitiveTopology::TriangleStrip => {
                 let mut primitives = vec![];
                 for (id, (v0, v1, v2)) in indices.iter().cloned().tuple_windows().enumerate() {
+                    // Skip degenerate triangles used to stitch strips together.
+                    if v0 == v1 || v1 == v2 || v2 == v0 {
+                        continue;
+                    }
                     let indices = if id & 1 == 0 {
                         [v0, v1, v2]
                     } else {
@@ -448,30 +560,194 @@ impl TryFrom<Mesh> for GpuMesh {
                 }
                 Ok(primitives)
             }
-            _ => Err(PrepareMeshError::IncompatiblePrimitiveTopology),
+            topology => Err(PrepareMeshError::IncompatiblePrimitiveTopology(topology)),
         }?;
 
         if primitives.is_empty() {
             return Err(PrepareMeshError::NoPrimitive);
         }
 
-        let bvh = BVH::build(&mut primitives);
-        let nodes = bvh.flatten_custom(&GpuNode::pack);
+        let mut hasher = DefaultHasher::new();
+        for primitive in &primitives {
+            primitive.indices.hash(&mut hasher);
+        }
+        let source_index_hash = hasher.finish();
+
+        if normals.is_none() {
+            // Give every face its own copy of its corners so that shared vertices don't
+            // blend the normals of neighbouring faces.
+            if u32::try_from(3 * primitives.len()).is_err() {
+                return Err(PrepareMeshError::TooManyVertices);
+            }
+            let mut flat_vertices = Vec::with_capacity(3 * primitives.len());
+            for primitive in &mut primitives {
+                let [v0, v1, v2] = primitive.vertices;
+                let normal = (v1 - v0).cross(v2 - v0).normalize_or_zero();
+                for index in &mut primitive.indices {
+                    let vertex = GpuVertex {
+                        normal,
+                        ..vertices[*index as usize]
+                    };
+                    *index = flat_vertices.len() as u32;
+                    flat_vertices.push(vertex);
+                }
+            }
+            vertices = flat_vertices;
+        }
 
         Ok(Self {
             vertices,
             primitives,
-            nodes,
+            nodes: vec![],
+            source_index_hash,
         })
     }
+
+    /// Updates bounds of BVH nodes from current vertex positions without changing the topology.
+    pub fn refit(&mut self) {
+        for primitive in &mut self.primitives {
+            primitive.vertices = primitive
+                .indices
+                .map(|id| self.vertices[id as usize].position);
+        }
+
+        // Nodes are flattened in pre-order, so children always come after their parents.
+        let mut aabbs = vec![AABB::empty(); self.nodes.len()];
+        for id in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[id];
+            if node.entry_index & 0x80000000 != 0 {
+                let primitive_index = (node.entry_index & !0x80000000) as usize;
+                aabbs[id] = self.primitives[primitive_index].aabb();
+            } else {
+                let mut aabb = AABB::empty();
+                let mut child = node.entry_index as usize;
+                while child < node.exit_index as usize {
+                    aabb.join_mut(&aabbs[child]);
+                    child = match self.nodes[child].entry_index & 0x80000000 {
+                        0 => self.nodes[child].exit_index as usize,
+                        _ => child + 1,
+                    };
+                }
+                self.nodes[id].min = aabb.min.to_array().into();
+                self.nodes[id].max = aabb.max.to_array().into();
+                aabbs[id] = aabb;
+            }
+        }
+    }
+
+    /// Converts a modified mesh asset.
+    /// The BVH is refitted rather than rebuilt if the primitives are unchanged.
+    pub fn try_update(&self, mesh: &Mesh) -> Result<Option<Self>, PrepareMeshError> {
+        let mut updated = Self::decode(mesh)?;
+        let same_topology = updated.source_index_hash == self.source_index_hash
+            && updated.primitives.len() == self.primitives.len()
+            && updated
+                .primitives
+                .iter()
+                .zip(self.primitives.iter())
+                .all(|(lhs, rhs)| lhs.indices == rhs.indices);
+
+        if same_topology && updated.vertices == self.vertices {
+            return Ok(None);
+        }
+
+        if same_topology {
+            for (primitive, old) in updated.primitives.iter_mut().zip(self.primitives.iter()) {
+                primitive.node_index = old.node_index;
+            }
+            updated.nodes = self.nodes.clone();
+            updated.refit();
+        } else {
+            updated.build_bvh();
+        }
+
+        debug_assert!(updated.validate().is_ok(), "{:?}", updated.validate());
+        Ok(Some(updated))
+    }
+
+    /// Checks that the flattened BVH is consistent, so that GPU traversal stays in bounds.
+    pub fn validate(&self) -> Result<(), BvhValidationError> {
+        let node_count = self.nodes.len() as u32;
+        for (node, value) in self.nodes.iter().enumerate() {
+            let node = node as u32;
+
+            // Traversal always moves forward and may only exit past the last node.
+            let exit_index = value.exit_index;
+            if exit_index <= node || exit_index > node_count {
+                return Err(BvhValidationError::ExitIndexOutOfRange { node, exit_index });
+            }
+
+            let entry_index = value.entry_index;
+            if entry_index & 0x80000000 != 0 {
+                let primitive_index = entry_index & !0x80000000;
+                if primitive_index as usize >= self.primitives.len() {
+                    return Err(BvhValidationError::PrimitiveIndexOutOfRange {
+                        node,
+                        primitive_index,
+                    });
+                }
+            } else if entry_index != node + 1 || entry_index >= node_count {
+                return Err(BvhValidationError::EntryIndexOutOfRange { node, entry_index });
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash of the vertex and index data, which fully determine the BVH.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for vertex in &self.vertices {
+            vertex
+                .position
+                .to_array()
+                .iter()
+                .chain(&vertex.normal.to_array())
+                .chain(&vertex.uv.to_array())
+                .chain(&vertex.tangent.to_array())
+                .chain(&vertex.color.to_array())
+                .for_each(|value| value.to_bits().hash(&mut hasher));
+        }
+        for primitive in &self.primitives {
+            primitive.indices.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Whether the vertex and index data covered by [`Self::content_hash`] are identical.
+    pub fn same_data(&self, other: &Self) -> bool {
+        self.vertices == other.vertices
+            && self.primitives.len() == other.primitives.len()
+            && self
+                .primitives
+                .iter()
+                .zip(other.primitives.iter())
+                .all(|(lhs, rhs)| lhs.indices == rhs.indices)
+    }
+
+    fn build_bvh(&mut self) {
+        let bvh = BVH::build(&mut self.primitives);
+        self.nodes = bvh.flatten_custom(&GpuNode::pack);
+    }
+}
+
+impl TryFrom<Mesh> for GpuMesh {
+    type Error = PrepareMeshError;
+
+    fn try_from(mesh: Mesh) -> Result<Self, Self::Error> {
+        let mut mesh = Self::decode(&mesh)?;
+        mesh.build_bvh();
+
+        debug_assert!(mesh.validate().is_ok(), "{:?}", mesh.validate());
+        Ok(mesh)
+    }
 }
 
-/// Offsets (and length for nodes) of the mesh in the universal buffer.
+/// Offsets and lengths of the mesh in the universal buffers, packed as `(offset, len)`.
 /// This is known only when [`MeshAssetState`] isn't [`Dirty`](MeshAssetState::Dirty).
 #[derive(Debug, Default, Clone, Copy, ShaderType)]
 pub struct GpuMeshIndex {
-    pub vertex: u32,
-    pub primitive: u32,
+    pub vertex: UVec2,
+    pub primitive: UVec2,
     pub node: UVec2,
 }
 
@@ -482,6 +758,9 @@ pub enum MeshMaterialSystems {
     PrepareInstances,
 }
 
+/// Layout of the mesh, instance and material storage buffers.
+/// Bindings are declared in `bevy_hikari::mesh_material_bindings`; custom passes can use this
+/// layout together with [`MeshMaterialBindGroup`] to traverse the BVHs with `bevy_hikari::bvh`.
 #[derive(Resource, Deref, DerefMut)]
 pub struct MeshMaterialBindGroupLayout(pub BindGroupLayout);
 
@@ -674,9 +953,12 @@ fn prepare_texture_bind_group_layout(
     }
 }
 
+/// Queued each frame; absent until all mesh, instance and material buffers are uploaded.
 #[derive(Resource)]
 pub struct MeshMaterialBindGroup {
+    /// Laid out by [`MeshMaterialBindGroupLayout`].
     pub mesh_material: BindGroup,
+    /// Laid out by [`TextureBindGroupLayout`].
     pub texture: BindGroup,
 }
 
@@ -822,3 +1104,256 @@ impl<const I: usize> EntityRenderCommand for SetMeshMaterialBindGroup<I> {
         RenderCommandResult::Success
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::render::mesh::Indices;
+
+    fn mesh(topology: PrimitiveTopology, positions: Vec<[f32; 3]>, indices: Vec<u16>) -> Mesh {
+        let mut mesh = Mesh::new(topology);
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_NORMAL,
+            vec![[0.0, 0.0, 1.0]; positions.len()],
+        );
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.set_indices(Some(Indices::U16(indices)));
+        mesh
+    }
+
+    fn primitive_indices(mesh: &GpuMesh) -> Vec<[u32; 3]> {
+        mesh.primitives
+            .iter()
+            .map(|primitive| primitive.indices)
+            .collect()
+    }
+
+    fn quad() -> Mesh {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+        ];
+        mesh(
+            PrimitiveTopology::TriangleList,
+            positions,
+            vec![0, 1, 2, 2, 1, 3],
+        )
+    }
+
+    #[test]
+    fn missing_normals_are_flat() {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+        ];
+        let mut mesh = mesh(
+            PrimitiveTopology::TriangleList,
+            positions,
+            vec![0, 1, 2, 0, 3, 1],
+        );
+        mesh.remove_attribute(Mesh::ATTRIBUTE_NORMAL);
+        let mesh = GpuMesh::try_from(mesh).unwrap();
+
+        // Shared vertices are split so that each face keeps its own normal.
+        assert_eq!(mesh.vertices.len(), 6);
+        assert_eq!(primitive_indices(&mesh), vec![[0, 1, 2], [3, 4, 5]]);
+        assert!(mesh.vertices[..3]
+            .iter()
+            .all(|vertex| vertex.normal == Vec3::Z));
+        assert!(mesh.vertices[3..]
+            .iter()
+            .all(|vertex| vertex.normal == Vec3::Y));
+        assert_eq!(mesh.vertices[3].position, Vec3::ZERO);
+        assert_eq!(mesh.vertices[4].position, Vec3::Z);
+    }
+
+    #[test]
+    fn missing_uvs_default_to_zero() {
+        let mesh = GpuMesh::try_from(quad()).unwrap();
+        assert!(mesh.vertices.iter().all(|vertex| vertex.uv == Vec2::ZERO));
+        assert!(mesh.vertices.iter().all(|vertex| vertex.normal == Vec3::Z));
+    }
+
+    #[test]
+    fn decode_triangle_list() {
+        let mesh = GpuMesh::try_from(quad()).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(primitive_indices(&mesh), vec![[0, 1, 2], [2, 1, 3]]);
+    }
+
+    #[test]
+    fn decode_triangle_strip() {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+        ];
+        let mesh = mesh(
+            PrimitiveTopology::TriangleStrip,
+            positions,
+            vec![0, 1, 2, 3],
+        );
+        let mesh = GpuMesh::try_from(mesh).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        // Every other triangle is flipped to keep the winding consistent.
+        assert_eq!(primitive_indices(&mesh), vec![[0, 1, 2], [2, 1, 3]]);
+    }
+
+    #[test]
+    fn refit_matches_rebuild() {
+        let positions: Vec<_> = (0..8)
+            .flat_map(|id| {
+                let x = 2.0 * id as f32;
+                [[x, 0.0, 0.0], [x + 1.0, 0.0, 0.0], [x, 1.0, 1.0]]
+            })
+            .collect();
+        let indices = (0..positions.len() as u16).collect();
+        let original = mesh(PrimitiveTopology::TriangleList, positions.clone(), indices);
+        let gpu_mesh = GpuMesh::try_from(original.clone()).unwrap();
+
+        let offset = Vec3::new(3.0, -2.0, 5.0);
+        let mut translated = original;
+        let positions: Vec<_> = positions
+            .iter()
+            .map(|position| (Vec3::from(*position) + offset).to_array())
+            .collect();
+        translated.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+
+        let refitted = gpu_mesh.try_update(&translated).unwrap().unwrap();
+        let rebuilt = GpuMesh::try_from(translated).unwrap();
+        assert!(refitted.validate().is_ok());
+
+        // The topology is kept, with every inner node moved along with the mesh.
+        // Leaves only refer to their primitives.
+        assert_eq!(refitted.nodes.len(), gpu_mesh.nodes.len());
+        for (node, old) in refitted.nodes.iter().zip(gpu_mesh.nodes.iter()) {
+            assert_eq!(node.entry_index, old.entry_index);
+            assert_eq!(node.exit_index, old.exit_index);
+            if node.entry_index & 0x80000000 == 0 {
+                assert!(node.min.abs_diff_eq(old.min + offset, 1e-5));
+                assert!(node.max.abs_diff_eq(old.max + offset, 1e-5));
+            }
+        }
+
+        let bounds = |mesh: &GpuMesh| {
+            mesh.nodes
+                .iter()
+                .filter(|node| node.entry_index & 0x80000000 == 0)
+                .fold(
+                    (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                    |(min, max), node| (min.min(node.min), max.max(node.max)),
+                )
+        };
+        let (min, max) = bounds(&refitted);
+        let (rebuilt_min, rebuilt_max) = bounds(&rebuilt);
+        assert!(min.abs_diff_eq(rebuilt_min, 1e-5));
+        assert!(max.abs_diff_eq(rebuilt_max, 1e-5));
+    }
+
+    #[test]
+    fn reindexed_flat_mesh_is_rebuilt() {
+        let positions: Vec<_> = (0..8)
+            .flat_map(|id| {
+                let x = 2.0 * id as f32;
+                [[x, 0.0, 0.0], [x + 1.0, 0.0, 0.0], [x, 1.0, 0.0]]
+            })
+            .collect();
+        let indices = (0..positions.len() as u16).collect();
+        let mut original = mesh(PrimitiveTopology::TriangleList, positions, indices);
+        original.remove_attribute(Mesh::ATTRIBUTE_NORMAL);
+        let gpu_mesh = GpuMesh::try_from(original.clone()).unwrap();
+
+        // Same vertices, with the triangles shuffled. Split vertices are indexed in order
+        // either way, so only the source indices tell the change.
+        let mut reindexed = original;
+        let indices = [0, 4, 1, 5, 2, 6, 3, 7]
+            .into_iter()
+            .flat_map(|id: u16| [3 * id, 3 * id + 1, 3 * id + 2])
+            .collect();
+        reindexed.set_indices(Some(Indices::U16(indices)));
+
+        let updated = gpu_mesh.try_update(&reindexed).unwrap().unwrap();
+        let rebuilt = GpuMesh::try_from(reindexed).unwrap();
+        assert_eq!(updated.nodes.len(), rebuilt.nodes.len());
+        for (node, rebuilt) in updated.nodes.iter().zip(rebuilt.nodes.iter()) {
+            assert_eq!(node.entry_index, rebuilt.entry_index);
+            assert_eq!(node.exit_index, rebuilt.exit_index);
+            assert_eq!(node.min, rebuilt.min);
+            assert_eq!(node.max, rebuilt.max);
+        }
+    }
+
+    #[test]
+    fn skip_degenerate_strip_triangles() {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [2.0, 0.0, 0.0],
+            [2.0, 1.0, 0.0],
+        ];
+        let indices = vec![0, 1, 2, 2, 3, 4];
+        let mesh = mesh(PrimitiveTopology::TriangleStrip, positions, indices);
+        let mesh = GpuMesh::try_from(mesh).unwrap();
+        assert_eq!(primitive_indices(&mesh), vec![[0, 1, 2], [3, 2, 4]]);
+    }
+
+    #[test]
+    fn mismatched_attribute_lengths() {
+        let mut mesh = quad();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0]; 3]);
+        assert!(matches!(
+            GpuMesh::try_from(mesh),
+            Err(PrepareMeshError::MismatchedAttributeLengths {
+                positions: 4,
+                normals: Some(4),
+                uvs: Some(3),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        let mut mesh = quad();
+        mesh.set_indices(Some(Indices::U16(vec![0, 1, 4])));
+        assert!(matches!(
+            GpuMesh::try_from(mesh),
+            Err(PrepareMeshError::IndexOutOfRange {
+                index: 4,
+                vertex_count: 4,
+            })
+        ));
+    }
+
+    #[test]
+    fn vertex_colors() {
+        let colors = vec![
+            [1.0, 0.0, 0.0, 1.0],
+            [0.0, 1.0, 0.0, 1.0],
+            [0.0, 0.0, 1.0, 0.5],
+            [1.0, 1.0, 0.0, 1.0],
+        ];
+        let mut mesh = quad();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors.clone());
+        let mesh = GpuMesh::try_from(mesh).unwrap();
+        for (vertex, color) in mesh.vertices.iter().zip(colors) {
+            assert_eq!(vertex.color, Vec4::from_array(color));
+        }
+
+        // Meshes without colors are white, leaving the base color as is.
+        let mesh = GpuMesh::try_from(quad()).unwrap();
+        assert!(mesh.vertices.iter().all(|vertex| vertex.color == Vec4::ONE));
+    }
+
+    #[test]
+    fn unchanged_mesh_needs_no_update() {
+        let mesh = GpuMesh::try_from(quad()).unwrap();
+        assert!(mesh.try_update(&quad()).unwrap().is_none());
+    }
+}
@@ -1,4 +1,4 @@
 pub use crate::{
-    mesh_material::{GenericInstancePlugin, GenericMaterialPlugin},
+    mesh_material::{ExcludeFromGi, GenericInstancePlugin, GenericMaterialPlugin},
     HikariPlugin, HikariSettings, HikariUniversalSettings, Taa, Upscale,
 };
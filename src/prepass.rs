@@ -45,6 +45,7 @@ pub const NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba8Snorm;
 pub const DEPTH_GRADIENT_FORMAT: TextureFormat = TextureFormat::Rg32Float;
 pub const INSTANCE_MATERIAL_FORMAT: TextureFormat = TextureFormat::Rg32Float;
 pub const VELOCITY_UV_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+pub const VERTEX_COLOR_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
 
 pub struct PrepassPlugin;
 impl Plugin for PrepassPlugin {
@@ -182,15 +183,24 @@ impl SpecializedMeshPipeline for PrepassPipeline {
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let vertex_attributes = vec![
-            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
-            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
-            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
-        ];
+        let mut vertex_attributes = vec![Mesh::ATTRIBUTE_POSITION.at_shader_location(0)];
+        let mut shader_defs = vec![];
+        if layout.contains(Mesh::ATTRIBUTE_NORMAL) {
+            vertex_attributes.push(Mesh::ATTRIBUTE_NORMAL.at_shader_location(1));
+            shader_defs.push("VERTEX_NORMALS".into());
+        }
+        if layout.contains(Mesh::ATTRIBUTE_UV_0) {
+            vertex_attributes.push(Mesh::ATTRIBUTE_UV_0.at_shader_location(2));
+            shader_defs.push("VERTEX_UVS".into());
+        }
+        if layout.contains(Mesh::ATTRIBUTE_COLOR) {
+            vertex_attributes.push(Mesh::ATTRIBUTE_COLOR.at_shader_location(3));
+            shader_defs.push("VERTEX_COLORS".into());
+        }
+
         let vertex_buffer_layout = layout.get_layout(&vertex_attributes)?;
         let bind_group_layout = vec![self.view_layout.clone(), self.mesh_layout.clone()];
 
-        let mut shader_defs = vec![];
         if key.temporal_anti_aliasing {
             shader_defs.push("TEMPORAL_ANTI_ALIASING".into());
         }
@@ -237,6 +247,11 @@ impl SpecializedMeshPipeline for PrepassPipeline {
                         blend: None,
                         write_mask: ColorWrites::ALL,
                     }),
+                    Some(ColorTargetState {
+                        format: VERTEX_COLOR_FORMAT,
+                        blend: None,
+                        write_mask: ColorWrites::ALL,
+                    }),
                 ],
             }),
             primitive: PrimitiveState {
@@ -303,6 +318,8 @@ pub struct PrepassTextures {
     pub previous_instance_material: Handle<Image>,
     #[texture(8, visibility(all))]
     pub previous_velocity_uv: Handle<Image>,
+    #[texture(9, visibility(all))]
+    pub vertex_color: Handle<Image>,
 }
 
 impl PrepassTextures {
@@ -332,6 +349,7 @@ pub struct PreparedPrepassTextures<'a> {
     pub depth_gradient: &'a GpuImage,
     pub instance_material: &'a GpuImage,
     pub velocity_uv: &'a GpuImage,
+    pub vertex_color: &'a GpuImage,
 }
 
 impl PrepassTextures {
@@ -345,6 +363,7 @@ impl PrepassTextures {
             depth_gradient: assets.get(&self.depth_gradient)?,
             instance_material: assets.get(&self.instance_material)?,
             velocity_uv: assets.get(&self.velocity_uv)?,
+            vertex_color: assets.get(&self.vertex_color)?,
         };
         Some(prepared)
     }
@@ -408,6 +427,7 @@ fn prepass_textures_system(
             let previous_normal = images.add(create_texture(NORMAL_FORMAT));
             let previous_instance_material = images.add(create_texture(INSTANCE_MATERIAL_FORMAT));
             let previous_velocity_uv = images.add(create_texture(VELOCITY_UV_FORMAT));
+            let vertex_color = images.add(create_texture(VERTEX_COLOR_FORMAT));
 
             commands.entity(entity).insert(PrepassTextures {
                 size,
@@ -420,6 +440,7 @@ fn prepass_textures_system(
                 previous_normal,
                 previous_instance_material,
                 previous_velocity_uv,
+                vertex_color,
             });
         }
     }
@@ -820,6 +841,11 @@ impl Node for PrepassNode {
                         resolve_target: None,
                         ops,
                     }),
+                    Some(RenderPassColorAttachment {
+                        view: &textures.vertex_color.texture_view,
+                        resolve_target: None,
+                        ops,
+                    }),
                 ],
                 depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
                     view: depth,
@@ -0,0 +1,218 @@
+#define_import_path bevy_hikari::bvh
+
+// Stackless traversal of the instance and mesh BVHs flattened by `GpuNode::pack`.
+// Import this after `bevy_hikari::mesh_material_bindings`, whose buffers it reads.
+
+let F32_EPSILON: f32 = 1.1920929E-7;
+let F32_MAX: f32 = 3.402823466E+38;
+let U32_MAX: u32 = 0xFFFFFFFFu;
+let BVH_LEAF_FLAG: u32 = 0x80000000u;
+
+// World space ray. `inv_direction` must be `1.0 / direction`.
+struct Ray {
+    origin: vec3<f32>,
+    direction: vec3<f32>,
+    inv_direction: vec3<f32>,
+};
+
+struct Aabb {
+    min: vec3<f32>,
+    max: vec3<f32>,
+};
+
+struct Intersection {
+    uv: vec2<f32>,
+    distance: f32,
+};
+
+// Closest hit along a ray. `instance_index` is `U32_MAX` on miss.
+struct Hit {
+    intersection: Intersection,
+    instance_index: u32,
+    primitive_index: u32,
+};
+
+fn instance_position_world_to_local(instance: Instance, p: vec3<f32>) -> vec3<f32> {
+    let inverse_model = transpose(instance.inverse_transpose_model);
+    let position = inverse_model * vec4<f32>(p, 1.0);
+    return position.xyz / position.w;
+}
+
+fn instance_direction_world_to_local(instance: Instance, p: vec3<f32>) -> vec3<f32> {
+    let inverse_model = transpose(instance.inverse_transpose_model);
+    let direction = inverse_model * vec4<f32>(p, 0.0);
+    return direction.xyz;
+}
+
+fn instance_position_local_to_world(instance: Instance, p: vec3<f32>) -> vec3<f32> {
+    let model = instance.model;
+    let position = model * vec4<f32>(p, 1.0);
+    return position.xyz / position.w;
+}
+
+fn instance_normal_local_to_world(instance: Instance, n: vec3<f32>) -> vec3<f32> {
+    // NOTE: The mikktspace method of normal mapping requires that the world normal is
+    // re-normalized in the vertex shader to match the way mikktspace bakes vertex tangents
+    // and normal maps so that the exact inverse process is applied when shading. Blender, Unity,
+    // Unreal Engine, Godot, and more all use the mikktspace method. Do not change this code
+    // unless you really know what you are doing.
+    // http://www.mikktspace.com/
+    return normalize(
+        mat3x3<f32>(
+            instance.inverse_transpose_model[0].xyz,
+            instance.inverse_transpose_model[1].xyz,
+            instance.inverse_transpose_model[2].xyz
+        ) * n
+    );
+}
+
+fn inside_aabb(p: vec3<f32>, aabb: Aabb) -> bool {
+    return all(p > aabb.min) && all(p < aabb.max);
+}
+
+fn intersects_aabb(ray: Ray, aabb: Aabb) -> f32 {
+    let t1 = (aabb.min - ray.origin) * ray.inv_direction;
+    let t2 = (aabb.max - ray.origin) * ray.inv_direction;
+
+    var t_min = min(t1.x, t2.x);
+    var t_max = max(t1.x, t2.x);
+
+    t_min = max(t_min, min(t1.y, t2.y));
+    t_max = min(t_max, max(t1.y, t2.y));
+
+    t_min = max(t_min, min(t1.z, t2.z));
+    t_max = min(t_max, max(t1.z, t2.z));
+
+    var t: f32 = F32_MAX;
+    if t_max >= t_min && t_max >= 0.0 {
+        t = t_min;
+    }
+    return t;
+}
+
+fn intersects_triangle(ray: Ray, tri: array<PrimitiveVertex, 3>) -> Intersection {
+    var result: Intersection;
+    result.distance = F32_MAX;
+
+    let ab = tri[1].position - tri[0].position;
+    let ac = tri[2].position - tri[0].position;
+
+    let u_vec = cross(ray.direction, ac);
+    let det = dot(ab, u_vec);
+    if abs(det) < F32_EPSILON {
+        return result;
+    }
+
+    let inv_det = 1.0 / det;
+    let ao = ray.origin - tri[0].position;
+    let u = dot(ao, u_vec) * inv_det;
+    if u < 0.0 || u > 1.0 {
+        result.uv = vec2<f32>(u, 0.0);
+        return result;
+    }
+
+    let v_vec = cross(ao, ab);
+    let v = dot(ray.direction, v_vec) * inv_det;
+    result.uv = vec2<f32>(u, v);
+    if v < 0.0 || u + v > 1.0 {
+        return result;
+    }
+
+    let distance = dot(ac, v_vec) * inv_det;
+    if distance > F32_EPSILON {
+        result.distance = distance;
+    }
+
+    return result;
+}
+
+// Traverses the BLAS of a mesh with a ray in its local space; returns whether `hit` got closer.
+fn traverse_bottom(hit: ptr<function, Hit>, ray: Ray, mesh: MeshIndex, early_distance: f32) -> bool {
+    var intersected = false;
+    var index = 0u;
+    for (; index < mesh.node.y;) {
+        let node_index = mesh.node.x + index;
+        let node = asset_node_buffer.data[node_index];
+        var aabb: Aabb;
+        if node.entry_index >= BVH_LEAF_FLAG {
+            let primitive_index = mesh.primitive.x + node.entry_index - BVH_LEAF_FLAG;
+            let vertices = primitive_buffer[primitive_index].vertices;
+
+            aabb.min = min(vertices[0].position, min(vertices[1].position, vertices[2].position));
+            aabb.max = max(vertices[0].position, max(vertices[1].position, vertices[2].position));
+
+            if intersects_aabb(ray, aabb) < (*hit).intersection.distance {
+                let intersection = intersects_triangle(ray, vertices);
+                if intersection.distance < (*hit).intersection.distance {
+                    (*hit).intersection = intersection;
+                    (*hit).primitive_index = primitive_index;
+                    intersected = true;
+
+                    if intersection.distance < early_distance {
+                        return intersected;
+                    }
+                }
+            }
+
+            index = node.exit_index;
+        } else {
+            aabb.min = node.min;
+            aabb.max = node.max;
+            index = select(
+                node.exit_index,
+                node.entry_index,
+                intersects_aabb(ray, aabb) < (*hit).intersection.distance
+            );
+        }
+    }
+
+    return intersected;
+}
+
+// Traverses the TLAS of all instances, skipping `exclude_instance`.
+// Returns as soon as a hit closer than `early_distance` is found (useful for shadow rays).
+fn traverse_top(ray: Ray, max_distance: f32, early_distance: f32, exclude_instance: u32) -> Hit {
+    var hit: Hit;
+    hit.intersection.distance = max_distance;
+    hit.instance_index = U32_MAX;
+    hit.primitive_index = U32_MAX;
+
+    var index = 0u;
+    for (; index < instance_node_buffer.count;) {
+        let node = instance_node_buffer.data[index];
+        var aabb: Aabb;
+
+        if node.entry_index >= BVH_LEAF_FLAG {
+            let instance_index = node.entry_index - BVH_LEAF_FLAG;
+            let instance = instance_buffer[instance_index];
+            aabb.min = instance.min;
+            aabb.max = instance.max;
+
+            if instance_index != exclude_instance && intersects_aabb(ray, aabb) < hit.intersection.distance {
+                var r: Ray;
+                r.origin = instance_position_world_to_local(instance, ray.origin);
+                r.direction = instance_direction_world_to_local(instance, ray.direction);
+                r.inv_direction = 1.0 / r.direction;
+
+                if traverse_bottom(&hit, r, instance.mesh, early_distance) {
+                    hit.instance_index = instance_index;
+                    if hit.intersection.distance < early_distance {
+                        return hit;
+                    }
+                }
+            }
+
+            index = node.exit_index;
+        } else {
+            aabb.min = node.min;
+            aabb.max = node.max;
+            index = select(
+                node.exit_index,
+                node.entry_index,
+                intersects_aabb(ray, aabb) < hit.intersection.distance
+            );
+        }
+    }
+
+    return hit;
+}
@@ -4,6 +4,7 @@
 #import bevy_pbr::lighting
 
 #import bevy_hikari::mesh_material_bindings
+#import bevy_hikari::bvh
 #import bevy_hikari::deferred_bindings
 
 #ifdef NO_TEXTURE
@@ -226,11 +227,6 @@ fn store_spatial_reservoir(index: i32, r: Reservoir) {
 let TAU: f32 = 6.283185307;
 let INV_TAU: f32 = 0.159154943;
 
-let F32_EPSILON: f32 = 1.1920929E-7;
-let F32_MAX: f32 = 3.402823466E+38;
-let U32_MAX: u32 = 0xFFFFFFFFu;
-let BVH_LEAF_FLAG: u32 = 0x80000000u;
-
 let RAY_BIAS: f32 = 0.02;
 let DISTANCE_MAX: f32 = 65535.0;
 let NOISE_TEXTURE_COUNT: u32 = 16u;
@@ -256,28 +252,6 @@ let DIRECT_VALIDATION_FRAME_SAMPLE_THRESHOLD: u32 = 4u;
 let SPATIAL_VARIANCE_SAMPLE_THRESHOLD: u32 = 4u;
 
 // -------- TRACING     ---------
-struct Ray {
-    origin: vec3<f32>,
-    direction: vec3<f32>,
-    inv_direction: vec3<f32>,
-};
-
-struct Aabb {
-    min: vec3<f32>,
-    max: vec3<f32>,
-};
-
-struct Intersection {
-    uv: vec2<f32>,
-    distance: f32,
-};
-
-struct Hit {
-    intersection: Intersection,
-    instance_index: u32,
-    primitive_index: u32,
-};
-
 struct Surface {
     base_color: vec4<f32>,
     emissive: vec4<f32>,
@@ -291,6 +265,7 @@ struct HitInfo {
     position: vec4<f32>,
     normal: vec3<f32>,
     uv: vec2<f32>,
+    color: vec4<f32>,
     instance_index: u32,
     material_index: u32,
 };
@@ -303,188 +278,6 @@ struct LightCandidate {
     p: f32,
 };
 
-fn instance_position_world_to_local(instance: Instance, p: vec3<f32>) -> vec3<f32> {
-    let inverse_model = transpose(instance.inverse_transpose_model);
-    let position = inverse_model * vec4<f32>(p, 1.0);
-    return position.xyz / position.w;
-}
-
-fn instance_direction_world_to_local(instance: Instance, p: vec3<f32>) -> vec3<f32> {
-    let inverse_model = transpose(instance.inverse_transpose_model);
-    let direction = inverse_model * vec4<f32>(p, 0.0);
-    return direction.xyz;
-}
-
-fn instance_position_local_to_world(instance: Instance, p: vec3<f32>) -> vec3<f32> {
-    let model = instance.model;
-    let position = model * vec4<f32>(p, 1.0);
-    return position.xyz / position.w;
-}
-
-fn instance_normal_local_to_world(instance: Instance, n: vec3<f32>) -> vec3<f32> {
-    // NOTE: The mikktspace method of normal mapping requires that the world normal is
-    // re-normalized in the vertex shader to match the way mikktspace bakes vertex tangents
-    // and normal maps so that the exact inverse process is applied when shading. Blender, Unity,
-    // Unreal Engine, Godot, and more all use the mikktspace method. Do not change this code
-    // unless you really know what you are doing.
-    // http://www.mikktspace.com/
-    return normalize(
-        mat3x3<f32>(
-            instance.inverse_transpose_model[0].xyz,
-            instance.inverse_transpose_model[1].xyz,
-            instance.inverse_transpose_model[2].xyz
-        ) * n
-    );
-}
-
-fn inside_aabb(p: vec3<f32>, aabb: Aabb) -> bool {
-    return all(p > aabb.min) && all(p < aabb.max);
-}
-
-fn intersects_aabb(ray: Ray, aabb: Aabb) -> f32 {
-    let t1 = (aabb.min - ray.origin) * ray.inv_direction;
-    let t2 = (aabb.max - ray.origin) * ray.inv_direction;
-
-    var t_min = min(t1.x, t2.x);
-    var t_max = max(t1.x, t2.x);
-
-    t_min = max(t_min, min(t1.y, t2.y));
-    t_max = min(t_max, max(t1.y, t2.y));
-
-    t_min = max(t_min, min(t1.z, t2.z));
-    t_max = min(t_max, max(t1.z, t2.z));
-
-    var t: f32 = F32_MAX;
-    if t_max >= t_min && t_max >= 0.0 {
-        t = t_min;
-    }
-    return t;
-}
-
-fn intersects_triangle(ray: Ray, tri: array<PrimitiveVertex, 3>) -> Intersection {
-    var result: Intersection;
-    result.distance = F32_MAX;
-
-    let ab = tri[1].position - tri[0].position;
-    let ac = tri[2].position - tri[0].position;
-
-    let u_vec = cross(ray.direction, ac);
-    let det = dot(ab, u_vec);
-    if abs(det) < F32_EPSILON {
-        return result;
-    }
-
-    let inv_det = 1.0 / det;
-    let ao = ray.origin - tri[0].position;
-    let u = dot(ao, u_vec) * inv_det;
-    if u < 0.0 || u > 1.0 {
-        result.uv = vec2<f32>(u, 0.0);
-        return result;
-    }
-
-    let v_vec = cross(ao, ab);
-    let v = dot(ray.direction, v_vec) * inv_det;
-    result.uv = vec2<f32>(u, v);
-    if v < 0.0 || u + v > 1.0 {
-        return result;
-    }
-
-    let distance = dot(ac, v_vec) * inv_det;
-    if distance > F32_EPSILON {
-        result.distance = distance;
-    }
-
-    return result;
-}
-
-fn traverse_bottom(hit: ptr<function, Hit>, ray: Ray, mesh: MeshIndex, early_distance: f32) -> bool {
-    var intersected = false;
-    var index = 0u;
-    for (; index < mesh.node.y;) {
-        let node_index = mesh.node.x + index;
-        let node = asset_node_buffer.data[node_index];
-        var aabb: Aabb;
-        if node.entry_index >= BVH_LEAF_FLAG {
-            let primitive_index = mesh.primitive + node.entry_index - BVH_LEAF_FLAG;
-            let vertices = primitive_buffer[primitive_index].vertices;
-
-            aabb.min = min(vertices[0].position, min(vertices[1].position, vertices[2].position));
-            aabb.max = max(vertices[0].position, max(vertices[1].position, vertices[2].position));
-
-            if intersects_aabb(ray, aabb) < (*hit).intersection.distance {
-                let intersection = intersects_triangle(ray, vertices);
-                if intersection.distance < (*hit).intersection.distance {
-                    (*hit).intersection = intersection;
-                    (*hit).primitive_index = primitive_index;
-                    intersected = true;
-
-                    if intersection.distance < early_distance {
-                        return intersected;
-                    }
-                }
-            }
-
-            index = node.exit_index;
-        } else {
-            aabb.min = node.min;
-            aabb.max = node.max;
-            index = select(
-                node.exit_index,
-                node.entry_index,
-                intersects_aabb(ray, aabb) < (*hit).intersection.distance
-            );
-        }
-    }
-
-    return intersected;
-}
-
-fn traverse_top(ray: Ray, max_distance: f32, early_distance: f32, exclude_instance: u32) -> Hit {
-    var hit: Hit;
-    hit.intersection.distance = max_distance;
-    hit.instance_index = U32_MAX;
-    hit.primitive_index = U32_MAX;
-
-    var index = 0u;
-    for (; index < instance_node_buffer.count;) {
-        let node = instance_node_buffer.data[index];
-        var aabb: Aabb;
-
-        if node.entry_index >= BVH_LEAF_FLAG {
-            let instance_index = node.entry_index - BVH_LEAF_FLAG;
-            let instance = instance_buffer[instance_index];
-            aabb.min = instance.min;
-            aabb.max = instance.max;
-
-            if instance_index != exclude_instance && intersects_aabb(ray, aabb) < hit.intersection.distance {
-                var r: Ray;
-                r.origin = instance_position_world_to_local(instance, ray.origin);
-                r.direction = instance_direction_world_to_local(instance, ray.direction);
-                r.inv_direction = 1.0 / r.direction;
-
-                if traverse_bottom(&hit, r, instance.mesh, early_distance) {
-                    hit.instance_index = instance_index;
-                    if hit.intersection.distance < early_distance {
-                        return hit;
-                    }
-                }
-            }
-
-            index = node.exit_index;
-        } else {
-            aabb.min = node.min;
-            aabb.max = node.max;
-            index = select(
-                node.exit_index,
-                node.entry_index,
-                intersects_aabb(ray, aabb) < hit.intersection.distance
-            );
-        }
-    }
-
-    return hit;
-}
-
 fn empty_hit_info(position: vec3<f32>, direction: vec3<f32>) -> HitInfo {
     var info: HitInfo;
     info.instance_index = U32_MAX;
@@ -502,9 +295,9 @@ fn hit_info(ray: Ray, hit: Hit) -> HitInfo {
         let instance = instance_buffer[hit.instance_index];
         let vertices = primitive_buffer[hit.primitive_index].vertices;
 
-        let v0 = vertex_buffer[(instance.mesh.vertex + vertices[0].index)];
-        let v1 = vertex_buffer[(instance.mesh.vertex + vertices[1].index)];
-        let v2 = vertex_buffer[(instance.mesh.vertex + vertices[2].index)];
+        let v0 = vertex_buffer[(instance.mesh.vertex.x + vertices[0].index)];
+        let v1 = vertex_buffer[(instance.mesh.vertex.x + vertices[1].index)];
+        let v2 = vertex_buffer[(instance.mesh.vertex.x + vertices[2].index)];
         let uv0 = vec2<f32>(v0.u, v0.v);
         let uv1 = vec2<f32>(v1.u, v1.v);
         let uv2 = vec2<f32>(v2.u, v2.v);
@@ -512,6 +305,7 @@ fn hit_info(ray: Ray, hit: Hit) -> HitInfo {
         info.uv = uv0 + uv.x * (uv1 - uv0) + uv.y * (uv2 - uv0);
         info.normal = v0.normal + uv.x * (v1.normal - v0.normal) + uv.y * (v2.normal - v0.normal);
         info.normal = instance_normal_local_to_world(instance, info.normal);
+        info.color = v0.color + uv.x * (v1.color - v0.color) + uv.y * (v2.color - v0.color);
 
         info.position = vec4<f32>(ray.origin + ray.direction * hit.intersection.distance, 1.0);
         info.material_index = instance.material;
@@ -664,7 +458,7 @@ fn select_light_candidate(
         let primitive_index = select(alias_index, alias_entry.index, rand.y < alias_entry.prob);
 
         let emissive_instance = instance_buffer[candidate.emissive_instance];
-        let v = primitive_buffer[emissive_instance.mesh.primitive + primitive_index].vertices;
+        let v = primitive_buffer[emissive_instance.mesh.primitive.x + primitive_index].vertices;
         let b = sample_uniform_triangle_barycentric(rand.zw);
         let p = instance_position_local_to_world(emissive_instance, b.x * v[0].position + b.y * v[1].position + (1.0 - b.x - b.y) * v[2].position);
 
@@ -793,6 +587,13 @@ fn retreive_emissive(material_index: u32, uv: vec2<f32>) -> vec4<f32> {
 }
 #endif
 
+// Surface seen directly through a pixel, tinted by the vertex color from the prepass.
+fn retreive_primary_surface(coords: vec2<i32>, material_index: u32, uv: vec2<f32>) -> Surface {
+    var surface = retreive_surface(material_index, uv);
+    surface.base_color *= textureLoad(vertex_color_texture, coords, 0);
+    return surface;
+}
+
 fn lit(
     radiance: vec3<f32>,
     diffuse_color: vec3<f32>,
@@ -1036,7 +837,7 @@ fn full_screen_albedo(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let instance_material = vec2<u32>(textureLoad(instance_material_texture, coords, 0).xy);
     let velocity_uv = textureLoad(velocity_uv_texture, coords, 0);
 
-    let surface = retreive_surface(instance_material.y, velocity_uv.zw);
+    let surface = retreive_primary_surface(coords, instance_material.y, velocity_uv.zw);
     let view_direction = calculate_view(position, view.projection[3].w == 1.0);
     textureStore(albedo_texture, coords, vec4<f32>(env_brdf(view_direction, normal, surface), 1.0));
 }
@@ -1230,7 +1031,7 @@ fn direct_lit(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         store_reservoir(coords.x + render_size.x * coords.y, r);
     }
 
-    let surface = retreive_surface(instance_material.y, velocity_uv.zw);
+    let surface = retreive_primary_surface(deferred_coords, instance_material.y, velocity_uv.zw);
     let view_direction = calculate_view(position, view.projection[3].w == 1.0);
 
     // if frame.enable_spatial_reuse == 0u {
@@ -1333,6 +1134,7 @@ fn indirect_lit_ambient(@builtin(global_invocation_id) invocation_id: vec3<u32>)
             var out_radiance = vec3<f32>(0.0);
 
             surface = retreive_surface(info.material_index, info.uv);
+            surface.base_color *= info.color;
             surface.roughness = 1.0;
 
             let candidate = select_light_candidate(
@@ -1409,6 +1211,7 @@ fn indirect_lit_ambient(@builtin(global_invocation_id) invocation_id: vec3<u32>)
         var out_radiance = vec3<f32>(0.0);
 
         surface = retreive_surface(info.material_index, info.uv);
+        surface.base_color *= info.color;
         surface.roughness = 1.0;
 
         let candidate = select_light_candidate(
@@ -1458,7 +1261,7 @@ fn indirect_lit_ambient(@builtin(global_invocation_id) invocation_id: vec3<u32>)
         store_previous_spatial_reservoir(previous_coords.x + render_size.x * previous_coords.y, r);
     }
 
-    surface = retreive_surface(instance_material.y, velocity_uv.zw);
+    surface = retreive_primary_surface(deferred_coords, instance_material.y, velocity_uv.zw);
     let view_direction = calculate_view(position, view.projection[3].w == 1.0);
     let sample_radiance = shading(
         view_direction,
@@ -1532,7 +1335,7 @@ fn spatial_reuse(
     let instance_material = vec2<u32>(textureLoad(instance_material_texture, deferred_coords, 0).xy);
     let velocity_uv = textureLoad(velocity_uv_texture, deferred_coords, 0);
 
-    let surface = retreive_surface(instance_material.y, velocity_uv.zw);
+    let surface = retreive_primary_surface(deferred_coords, instance_material.y, velocity_uv.zw);
 
     let use_spatial_variance = r.count <= f32(SPATIAL_VARIANCE_SAMPLE_THRESHOLD);
 
@@ -5,6 +5,8 @@ struct Vertex {
     u: f32,
     normal: vec3<f32>,
     v: f32,
+    tangent: vec4<f32>,     // zero if the mesh has no tangents
+    color: vec4<f32>,       // white if the mesh has no vertex colors
 };
 
 struct PrimitiveVertex {
@@ -17,9 +19,9 @@ struct Primitive {
 };
 
 struct MeshIndex {
-    vertex: u32,
-    primitive: u32,
-    node: vec2<u32>,    // x: offset, y: size
+    vertex: vec2<u32>,      // x: offset, y: size
+    primitive: vec2<u32>,   // x: offset, y: size
+    node: vec2<u32>,        // x: offset, y: size
 };
 
 struct Instance {
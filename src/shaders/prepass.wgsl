@@ -15,16 +15,26 @@ let PI: f32 = 3.1415926;
 
 struct Vertex {
     @location(0) position: vec3<f32>,
+#ifdef VERTEX_NORMALS
     @location(1) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_UVS
     @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(3) color: vec4<f32>,
+#endif
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) world_position: vec4<f32>,
     @location(1) previous_world_position: vec4<f32>,
+#ifdef VERTEX_NORMALS
     @location(2) world_normal: vec3<f32>,
+#endif
     @location(3) uv: vec2<f32>,
+    @location(4) color: vec4<f32>,
 };
 
 fn frame_jitter() -> vec2<f32> {
@@ -64,9 +74,20 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     // jitter = 0.5 * jitter + select(-0.5, 0.5, frame.number % 2u == 0u) * texel_size;
 #endif // SMAA_TU_4X
 
+#ifdef VERTEX_NORMALS
     out.world_normal = mesh_normal_local_to_world(vertex.normal);
+#endif
     out.clip_position = view.view_proj * out.world_position;
+#ifdef VERTEX_UVS
     out.uv = vertex.uv;
+#else
+    out.uv = vec2<f32>(0.0);
+#endif
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#else
+    out.color = vec4<f32>(1.0);
+#endif
 
     out.clip_position += vec4<f32>(jitter.x, -jitter.y, 0.0, 0.0) * out.clip_position.w;
 
@@ -79,6 +100,7 @@ struct FragmentOutput {
     @location(2) depth_gradient: vec2<f32>,
     @location(3) instance_material: vec2<f32>,
     @location(4) velocity_uv: vec4<f32>,
+    @location(5) color: vec4<f32>,
 };
 
 @fragment
@@ -86,7 +108,14 @@ fn fragment(in: VertexOutput) -> FragmentOutput {
     var out: FragmentOutput;
 
     out.position = vec4<f32>(in.world_position.xyz, in.clip_position.z);
+#ifdef VERTEX_NORMALS
     out.normal = vec4<f32>(in.world_normal, 1.0);
+#else
+    // Flat face normal from screen-space derivatives, facing the camera.
+    let world_position = in.world_position.xyz;
+    let face_normal = normalize(cross(dpdy(world_position), dpdx(world_position)));
+    out.normal = vec4<f32>(face_normal, 1.0);
+#endif
     out.depth_gradient = vec2<f32>(dpdx(in.clip_position.z), dpdy(in.clip_position.z));
 
     let instance = f32(instance_index.instance) + 0.5;
@@ -95,6 +124,7 @@ fn fragment(in: VertexOutput) -> FragmentOutput {
 
     let velocity = clip_to_uv(view.view_proj * in.world_position) - clip_to_uv(previous_view.view_proj * in.previous_world_position);
     out.velocity_uv = vec4<f32>(velocity, in.uv);
+    out.color = in.color;
 
     return out;
 }